@@ -0,0 +1,69 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use log::info;
+use serde::Serialize;
+
+/// One line per connection, for auditing who reached what.
+#[derive(Debug, Serialize)]
+pub struct AccessRecord {
+    pub client: SocketAddr,
+    pub protocol: Option<&'static str>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub identity: Option<String>,
+    pub resolved: Vec<SocketAddr>,
+    pub connected: Option<SocketAddr>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+
+    #[serde(skip)]
+    started: Instant,
+    /// health checks and the like aren't interesting
+    #[serde(skip)]
+    pub quiet: bool,
+}
+
+impl AccessRecord {
+    pub fn new(client: SocketAddr) -> AccessRecord {
+        AccessRecord {
+            client,
+            protocol: None,
+            host: None,
+            port: None,
+            identity: None,
+            resolved: Vec::new(),
+            connected: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration_ms: 0,
+            error: None,
+            started: Instant::now(),
+            quiet: false,
+        }
+    }
+
+    /// A fresh record for another destination reached over the same connection.
+    pub fn next(&self) -> AccessRecord {
+        AccessRecord {
+            protocol: self.protocol,
+            identity: self.identity.clone(),
+            quiet: self.quiet,
+            ..AccessRecord::new(self.client)
+        }
+    }
+
+    pub fn finish(mut self, error: Option<&anyhow::Error>) {
+        if self.quiet {
+            return;
+        }
+        self.duration_ms = self.started.elapsed().as_millis() as u64;
+        self.error = error.map(|e| format!("{:#}", e));
+        match serde_json::to_string(&self) {
+            Ok(line) => info!(target: "begonia::access", "{}", line),
+            Err(err) => info!(target: "begonia::access", "unserialisable {:?}: {:?}", self, err),
+        }
+    }
+}
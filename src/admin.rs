@@ -0,0 +1,226 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::auth::Authenticator;
+use crate::health::Health;
+use crate::http;
+use crate::metrics;
+use crate::sessions::Registry;
+
+/// Give up on admin clients which haven't sent a request by this point.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST: usize = 16 * 1024;
+
+/// Health, metrics, and anything else for operators, kept away from the proxy port.
+#[derive(Clone)]
+pub struct Admin {
+    health: Health,
+    sessions: Registry,
+    auth: Authenticator,
+    /// who may see and close other people's connections; nobody, if empty
+    operators: Arc<Vec<String>>,
+}
+
+/// The parts of a request we act on.
+struct Request {
+    method: String,
+    path: String,
+    bearer: Option<String>,
+}
+
+impl Admin {
+    pub fn new(
+        health: Health,
+        sessions: Registry,
+        auth: Authenticator,
+        operators: Vec<String>,
+    ) -> Admin {
+        Admin {
+            health,
+            sessions,
+            auth,
+            operators: Arc::new(operators),
+        }
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (socket, client_addr) = listener.accept().await?;
+            let admin = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.handle(socket).await {
+                    debug!("{:?} handling admin request from {:?}", e, client_addr);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut socket: TcpStream) -> Result<()> {
+        let req = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut socket))
+            .await
+            .map_err(|_| anyhow!("no request after {:?}", REQUEST_TIMEOUT))??;
+        let msg = self.respond(&req).await?;
+        socket.write_all(&msg).await?;
+        socket.shutdown().await?;
+        Ok(())
+    }
+
+    /// The whole response, with the connection closed after it.
+    async fn respond(&self, req: &Request) -> Result<Vec<u8>> {
+        let path = req.path.split('?').next().unwrap_or_default();
+        if path == "/connections" || path.starts_with("/connections/") {
+            if let Some(refused) = self.authorise(req).await {
+                return Ok(refused);
+            }
+        }
+        if let Some(id) = path.strip_prefix("/connections/") {
+            return Ok(self.terminate(&req.method, id));
+        }
+        if req.method != "GET" {
+            return Ok(response(
+                "405 Method Not Allowed",
+                "text/plain",
+                "GET only\n",
+            ));
+        }
+        Ok(match path {
+            "/" | "/healthcheck" => legacy_health(&self.health, path),
+            "/livez" => response("200 OK", "application/json", "{\"ok\":true}"),
+            "/readyz" => {
+                let report = self.health.readiness().await;
+                let status = if report.ok {
+                    "200 OK"
+                } else {
+                    "503 Service Unavailable"
+                };
+                response(status, "application/json", &serde_json::to_string(&report)?)
+            }
+            "/connections" => response(
+                "200 OK",
+                "application/json",
+                &serde_json::to_string(&self.sessions.list())?,
+            ),
+            "/metrics" => response("200 OK", "text/plain; version=0.0.4", &metrics::render()),
+            _ => response("404 Not Found", "text/plain", "not found\n"),
+        })
+    }
+}
+
+impl Admin {
+    /// The response refusing the request, unless it's from one of the operators.
+    async fn authorise(&self, req: &Request) -> Option<Vec<u8>> {
+        let token = match &req.bearer {
+            Some(token) => token,
+            None => return Some(unauthorised()),
+        };
+        let identity = match self.auth.review(token).await {
+            Ok(Some(identity)) => identity,
+            Ok(None) => return Some(unauthorised()),
+            Err(err) => {
+                warn!("reviewing admin token: {:?}", err);
+                return Some(response(
+                    "503 Service Unavailable",
+                    "text/plain",
+                    "couldn't check token\n",
+                ));
+            }
+        };
+        if !self.operators.contains(&identity.username) {
+            info!("{:?} isn't an operator", identity.username);
+            return Some(response("403 Forbidden", "text/plain", "not an operator\n"));
+        }
+        None
+    }
+
+    /// `DELETE /connections/{id}`
+    fn terminate(&self, method: &str, id: &str) -> Vec<u8> {
+        if method != "DELETE" {
+            return response("405 Method Not Allowed", "text/plain", "DELETE only\n");
+        }
+        let id = match id.parse::<u64>() {
+            Ok(id) => id,
+            Err(_) => return response("400 Bad Request", "text/plain", "bad connection id\n"),
+        };
+        if !self.sessions.terminate(id) {
+            return response("404 Not Found", "text/plain", "no such connection\n");
+        }
+        info!("terminating connection {} on request", id);
+        response("200 OK", "application/json", "{\"ok\":true}")
+    }
+}
+
+/// All that's answered on the proxy port, for older probes; nothing about other clients.
+pub fn legacy_health(health: &Health, path: &str) -> Vec<u8> {
+    match path {
+        "/" => response("200 OK", "text/plain", env!("CARGO_CRATE_NAME")),
+        // only fails when draining, as an outage elsewhere isn't worth a restart
+        "/healthcheck" if health.is_draining() => {
+            response("503 Draining", "application/json", "{\"ok\":false}")
+        }
+        "/healthcheck" => response("200 OK", "application/json", "{\"ok\":true}"),
+        _ => response("404 Not Found", "text/plain", "not found\n"),
+    }
+}
+
+async fn read_request(socket: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if buf.len() >= MAX_REQUEST {
+            bail!("request longer than {} bytes", MAX_REQUEST);
+        }
+        buf.reserve(1024);
+        if 0 == socket.read_buf(&mut buf).await? {
+            bail!("unexpected eof reading request");
+        }
+        let mut headers = vec![httparse::EMPTY_HEADER; 16];
+        let (req, status) = http::parse_request(&mut headers, &buf)?;
+        if status.is_partial() {
+            continue;
+        }
+        let method = req.method.ok_or(anyhow!("no method on a valid request?"))?;
+        let path = req.path.ok_or(anyhow!("no path on a valid request?"))?;
+        let bearer = http::header(req.headers, "authorization")
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim().to_string());
+        return Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            bearer,
+        });
+    }
+}
+
+fn unauthorised() -> Vec<u8> {
+    with_headers(
+        "401 Unauthorized",
+        "WWW-Authenticate: Bearer\r\n",
+        "text/plain",
+        "bearer token required\n",
+    )
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    with_headers(status, "", content_type, body)
+}
+
+/// `extra` is whole header lines, each ending in `\r\n`
+fn with_headers(status: &str, extra: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.0 {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        extra,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
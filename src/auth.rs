@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use kube::api::PostParams;
+use kube::Api;
+use kube::Client;
+use log::debug;
+
+/// Successful reviews are remembered for this long, so a client opening many connections
+/// doesn't cost a `TokenReview` each.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub username: String,
+}
+
+#[derive(Clone)]
+pub struct Authenticator {
+    client: Client,
+    pub required: bool,
+    cache: Arc<Mutex<HashMap<String, (Identity, Instant)>>>,
+}
+
+impl Authenticator {
+    pub fn new(client: Client, required: bool) -> Authenticator {
+        Authenticator {
+            client,
+            required,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Check a bearer token against the apiserver, `None` if it isn't valid.
+    pub async fn review(&self, token: &str) -> Result<Option<Identity>> {
+        if let Some((identity, at)) = self.cache.lock().expect("poisoned").get(token) {
+            if at.elapsed() < CACHE_TTL {
+                return Ok(Some(identity.clone()));
+            }
+        }
+
+        let review = TokenReview {
+            spec: TokenReviewSpec {
+                token: Some(token.to_string()),
+                ..TokenReviewSpec::default()
+            },
+            ..TokenReview::default()
+        };
+        let status = Api::<TokenReview>::all(self.client.clone())
+            .create(&PostParams::default(), &review)
+            .await
+            .with_context(|| anyhow!("creating token review"))?
+            .status
+            .unwrap_or_default();
+
+        if !status.authenticated.unwrap_or(false) {
+            debug!("token rejected: {:?}", status.error);
+            return Ok(None);
+        }
+
+        let identity = Identity {
+            username: status.user.and_then(|u| u.username).unwrap_or_default(),
+        };
+
+        let mut cache = self.cache.lock().expect("poisoned");
+        cache.retain(|_, (_, at)| at.elapsed() < CACHE_TTL);
+        cache.insert(token.to_string(), (identity.clone(), Instant::now()));
+
+        Ok(Some(identity))
+    }
+}
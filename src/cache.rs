@@ -0,0 +1,308 @@
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use k8s_openapi::api::core::v1::{
+    Endpoints, Node, NodeStatus, Pod, PodStatus, Service, ServiceSpec,
+};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::ListParams;
+use kube::runtime::reflector::{self, ObjectRef, Store};
+use kube::runtime::watcher;
+use kube::{Api, Client, Resource};
+use log::{debug, info, warn};
+use serde::de::DeserializeOwned;
+
+use crate::endpoints::{self, EndpointGroup};
+
+/// How long to wait before watching again, after a watch fails.
+pub const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A watch-maintained copy of some kind of object, which is "cold" (and should not be
+/// trusted, even to say an object doesn't exist) until the first full list has arrived.
+#[derive(Clone)]
+pub struct Reflected<K: Resource<DynamicType = ()> + 'static> {
+    store: Store<K>,
+    warm: Arc<AtomicBool>,
+}
+
+impl<K> Reflected<K>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    /// `slim` drops whatever we don't read before the object is stored, as a whole
+    /// cluster's worth of some kinds is a lot of memory.
+    fn start(client: &Client, slim: fn(&mut K)) -> Reflected<K> {
+        let (store, writer) = reflector::store();
+        let warm = Arc::new(AtomicBool::new(false));
+        let reflected = Reflected {
+            store,
+            warm: warm.clone(),
+        };
+
+        let kind = K::kind(&());
+        let stream = reflector::reflector(
+            writer,
+            watcher(Api::<K>::all(client.clone()), ListParams::default())
+                .map_ok(move |event| event.modify(slim)),
+        );
+        tokio::spawn(async move {
+            let mut stream = stream.boxed();
+            while let Some(event) = stream.next().await {
+                match event {
+                    Ok(watcher::Event::Restarted(objects)) => {
+                        debug!("cache of {} (re)listed: {} objects", kind, objects.len());
+                        if !warm.swap(true, Ordering::SeqCst) {
+                            info!("cache of {} is warm", kind);
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(err) => {
+                        warn!("watching {}: {:?}", kind, err);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        reflected
+    }
+
+    /// never warm, so every lookup falls through to the apiserver
+    fn cold() -> Reflected<K> {
+        let (store, _writer) = reflector::store();
+        Reflected {
+            store,
+            warm: Arc::default(),
+        }
+    }
+
+    fn is_warm(&self) -> bool {
+        self.warm.load(Ordering::SeqCst)
+    }
+
+    /// `None` if the cache is cold, `Some(None)` if the object doesn't exist
+    pub fn get(&self, ns: &str, name: &str) -> Option<Option<Arc<K>>> {
+        if !self.is_warm() {
+            return None;
+        }
+        Some(self.store.get(&ObjectRef::new(name).within(ns)))
+    }
+
+    /// All objects matching the predicate, `None` if the cache is cold
+    pub fn filter(&self, predicate: impl Fn(&K) -> bool) -> Option<Vec<Arc<K>>> {
+        if !self.is_warm() {
+            return None;
+        }
+        Some(
+            self.store
+                .state()
+                .into_iter()
+                .filter(|obj| predicate(obj))
+                .collect(),
+        )
+    }
+}
+
+/// In-memory copies of the objects resolution and policy need, so most requests don't hit
+/// the apiserver.
+#[derive(Clone)]
+pub struct Cache {
+    pub services: Reflected<Service>,
+    pub pods: Reflected<Pod>,
+    pub nodes: Reflected<Node>,
+    endpoints: EndpointSource,
+}
+
+/// Only one of these is watched, depending on what the cluster supports.
+#[derive(Clone)]
+enum EndpointSource {
+    Slices(Reflected<EndpointSlice>),
+    Legacy(Reflected<Endpoints>),
+}
+
+impl Cache {
+    /// Nodes are only needed to block connections to them, so are only watched if `nodes`.
+    pub async fn start(client: &Client, nodes: bool) -> Cache {
+        let endpoints = if endpoints::slices_supported(client).await {
+            EndpointSource::Slices(Reflected::start(client, slim_slice))
+        } else {
+            info!("endpointslices unavailable, caching endpoints");
+            EndpointSource::Legacy(Reflected::start(client, slim_endpoints))
+        };
+
+        Cache {
+            services: Reflected::start(client, slim_service),
+            pods: Reflected::start(client, slim_pod),
+            nodes: if nodes {
+                Reflected::start(client, slim_node)
+            } else {
+                Reflected::cold()
+            },
+            endpoints,
+        }
+    }
+
+    pub fn disabled() -> Cache {
+        Cache {
+            services: Reflected::cold(),
+            pods: Reflected::cold(),
+            nodes: Reflected::cold(),
+            endpoints: EndpointSource::Slices(Reflected::cold()),
+        }
+    }
+
+    /// The usable endpoints of a service, `None` if the cache is cold
+    pub fn endpoint_groups(&self, ns: &str, service: &str) -> Option<Result<Vec<EndpointGroup>>> {
+        match &self.endpoints {
+            EndpointSource::Slices(slices) => {
+                let slices = slices.filter(|slice| {
+                    slice.metadata.namespace.as_deref() == Some(ns)
+                        && slice
+                            .metadata
+                            .labels
+                            .as_ref()
+                            .and_then(|labels| labels.get("kubernetes.io/service-name"))
+                            .map(String::as_str)
+                            == Some(service)
+                })?;
+                if slices.is_empty() {
+                    return Some(Err(anyhow!("no endpointslices for {}/{}", ns, service)));
+                }
+                Some(endpoints::from_slices(&slices))
+            }
+            EndpointSource::Legacy(legacy) => Some(match legacy.get(ns, service)? {
+                Some(found) => endpoints::from_endpoints(&found),
+                None => Err(anyhow!("no endpoints {}/{}", ns, service)),
+            }),
+        }
+    }
+
+    /// Pods (in the namespace, if given) with this ip, as any of theirs, not just the primary.
+    pub async fn pods_with_ip(
+        &self,
+        client: &Client,
+        ns: Option<&str>,
+        ip: IpAddr,
+    ) -> Result<Vec<Arc<Pod>>> {
+        let ip = ip.to_string();
+        let cached = self.pods.filter(|pod| {
+            ns.is_none_or(|ns| pod.metadata.namespace.as_deref() == Some(ns)) && has_ip(pod, &ip)
+        });
+        if let Some(pods) = cached {
+            return Ok(pods);
+        }
+
+        let api = match ns {
+            Some(ns) => Api::<Pod>::namespaced(client.clone(), ns),
+            None => Api::<Pod>::all(client.clone()),
+        };
+        let by_primary = api
+            .list(&ListParams::default().fields(&format!("status.podIP={}", ip)))
+            .await
+            .with_context(|| anyhow!("listing pods with ip {}", ip))?
+            .items;
+        if !by_primary.is_empty() {
+            return Ok(by_primary.into_iter().map(Arc::new).collect());
+        }
+        // the selector only sees the primary ip, e.g. not the ipv6 one on a dual-stack cluster
+        Ok(api
+            .list(&ListParams::default())
+            .await
+            .with_context(|| anyhow!("listing pods for ip {}", ip))?
+            .items
+            .into_iter()
+            .filter(|pod| has_ip(pod, &ip))
+            .map(Arc::new)
+            .collect())
+    }
+}
+
+fn has_ip(pod: &Pod, ip: &str) -> bool {
+    pod.status.as_ref().is_some_and(|status| {
+        status.pod_ip.as_deref() == Some(ip)
+            || status
+                .pod_ips
+                .iter()
+                .flatten()
+                .any(|p| p.ip.as_deref() == Some(ip))
+    })
+}
+
+/// Just what identifies the object, and its labels if they're wanted.
+fn slim_metadata(meta: &mut ObjectMeta, labels: bool) {
+    *meta = ObjectMeta {
+        name: meta.name.take(),
+        namespace: meta.namespace.take(),
+        resource_version: meta.resource_version.take(),
+        labels: meta.labels.take().filter(|_| labels),
+        ..ObjectMeta::default()
+    };
+}
+
+/// labels for policy, and ips for resolution and policy
+fn slim_pod(pod: &mut Pod) {
+    slim_metadata(&mut pod.metadata, true);
+    pod.spec = None;
+    pod.status = pod.status.take().map(|status| PodStatus {
+        pod_ip: status.pod_ip,
+        pod_ips: status.pod_ips,
+        ..PodStatus::default()
+    });
+}
+
+/// ports for resolution; ips and the selector for policy
+fn slim_service(service: &mut Service) {
+    slim_metadata(&mut service.metadata, false);
+    service.spec = service.spec.take().map(|spec| ServiceSpec {
+        cluster_ip: spec.cluster_ip,
+        cluster_ips: spec.cluster_ips,
+        ports: spec.ports,
+        selector: spec.selector,
+        ..ServiceSpec::default()
+    });
+    service.status = None;
+}
+
+/// found by the service name label
+fn slim_slice(slice: &mut EndpointSlice) {
+    slim_metadata(&mut slice.metadata, true);
+    for endpoint in &mut slice.endpoints {
+        endpoint.deprecated_topology = None;
+        endpoint.hints = None;
+        endpoint.hostname = None;
+        endpoint.node_name = None;
+        endpoint.target_ref = None;
+        endpoint.zone = None;
+    }
+}
+
+fn slim_endpoints(endpoints: &mut Endpoints) {
+    slim_metadata(&mut endpoints.metadata, false);
+    for subset in endpoints.subsets.iter_mut().flatten() {
+        subset.not_ready_addresses = None;
+        for address in subset.addresses.iter_mut().flatten() {
+            address.hostname = None;
+            address.node_name = None;
+            address.target_ref = None;
+        }
+    }
+}
+
+/// only the addresses, for blocking; the status is mostly images and conditions
+fn slim_node(node: &mut Node) {
+    slim_metadata(&mut node.metadata, false);
+    node.spec = None;
+    node.status = node.status.take().map(|status| NodeStatus {
+        addresses: status.addresses,
+        ..NodeStatus::default()
+    });
+}
@@ -0,0 +1,357 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+
+use crate::connect::Strategy;
+use crate::policy::Policy;
+use crate::resolve::DnsCacheOptions;
+use crate::timeouts::Timeouts;
+
+/// Settings, from the config file, overridden by environment variables, then flags.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// health, metrics and the like
+    pub admin_listen: SocketAddr,
+    /// usernames whose tokens may list and close connections through the admin listener
+    pub admin_users: Vec<String>,
+    /// also answer `GET /healthcheck` on the proxy port, for older probes
+    pub legacy_health: bool,
+    /// accept connections redirected by the firewall here, if set
+    pub transparent_listen: Option<SocketAddr>,
+    /// how they were redirected, which decides how we find where they were going
+    pub transparent_mode: TransparentMode,
+    pub cluster_domain: String,
+    /// `None` for the pod's own namespace, or the kubeconfig context's
+    pub default_namespace: Option<String>,
+
+    /// where to find the cluster dns
+    pub dns_namespace: String,
+    pub dns_service: String,
+    pub dns_port: u16,
+
+    pub dns_cache_size: Option<usize>,
+    pub dns_min_ttl_secs: Option<u64>,
+    pub dns_max_ttl_secs: Option<u64>,
+    pub dns_negative_max_ttl_secs: Option<u64>,
+
+    /// watch services, endpoints and pods, instead of fetching them per request
+    pub watch_cache: bool,
+
+    pub require_auth: bool,
+    /// refuse loopback, link-local, cloud metadata, node and our own addresses
+    pub block_internal: bool,
+
+    pub egress: EgressMode,
+
+    /// which of a target's addresses to try first
+    pub endpoint_selection: Selection,
+    /// give up on an address after this long, and try the next
+    pub connect_attempt_timeout_ms: u64,
+    /// start trying the next address if the last hasn't connected after this long
+    pub connect_stagger_ms: u64,
+
+    pub handshake_timeout_secs: u64,
+    pub resolve_timeout_secs: u64,
+    /// across all the attempts
+    pub connect_timeout_secs: u64,
+    /// zero disables
+    pub idle_timeout_secs: u64,
+    /// zero disables
+    pub max_tunnel_lifetime_secs: u64,
+    /// how long to let open connections finish after SIGTERM
+    pub drain_timeout_secs: u64,
+
+    /// only from the config file, as `[policy]` and `[[policy.rule]]` tables
+    pub policy: Policy,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Selection {
+    Random,
+    /// rotate through the addresses, across all targets
+    RoundRobin,
+    /// the address we have the fewest tunnels open to
+    LeastConnections,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressMode {
+    /// connect to pods directly, from inside the cluster
+    Direct,
+    /// reach pods through the apiserver's port-forwarding, e.g. from a laptop
+    Apiserver,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TransparentMode {
+    /// iptables or nftables `REDIRECT`, which we undo with `SO_ORIGINAL_DST`
+    Redirect,
+    /// `TPROXY`, where the connection arrives still addressed to its destination
+    Tproxy,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "[::]:3438".parse().expect("static address"),
+            admin_listen: "[::]:3439".parse().expect("static address"),
+            admin_users: Vec::new(),
+            legacy_health: false,
+            transparent_listen: None,
+            transparent_mode: TransparentMode::Redirect,
+            cluster_domain: "cluster.local".to_string(),
+            default_namespace: None,
+            dns_namespace: "kube-system".to_string(),
+            dns_service: "kube-dns".to_string(),
+            dns_port: 53,
+            dns_cache_size: None,
+            dns_min_ttl_secs: None,
+            dns_max_ttl_secs: None,
+            dns_negative_max_ttl_secs: None,
+            watch_cache: true,
+            require_auth: false,
+            block_internal: true,
+            egress: EgressMode::Direct,
+            endpoint_selection: Selection::Random,
+            connect_attempt_timeout_ms: 3000,
+            connect_stagger_ms: 250,
+            handshake_timeout_secs: 10,
+            resolve_timeout_secs: 10,
+            connect_timeout_secs: 30,
+            idle_timeout_secs: 3600,
+            max_tunnel_lifetime_secs: 0,
+            drain_timeout_secs: 25,
+            policy: Policy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// toml file to read settings from; any flag can also be set there
+    #[arg(long, env = "BEGONIA_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// address to accept proxy connections on [default: [::]:3438]
+    #[arg(long, env = "BEGONIA_LISTEN")]
+    listen: Option<SocketAddr>,
+
+    /// address to serve health, metrics and admin requests on [default: [::]:3439]
+    #[arg(long, env = "BEGONIA_ADMIN_LISTEN")]
+    admin_listen: Option<SocketAddr>,
+
+    /// comma-separated usernames whose tokens may list and close connections, e.g.
+    /// `system:serviceaccount:ops:oncall` [default: none]
+    #[arg(long, env = "BEGONIA_ADMIN_USERS", value_delimiter = ',')]
+    admin_users: Option<Vec<String>>,
+
+    /// also answer `GET /healthcheck` on the proxy port, for older probes [default: false]
+    #[arg(long, env = "BEGONIA_LEGACY_HEALTH")]
+    legacy_health: Option<bool>,
+
+    /// address to accept connections redirected by iptables or nftables on
+    #[arg(long, env = "BEGONIA_TRANSPARENT_LISTEN")]
+    transparent_listen: Option<SocketAddr>,
+
+    /// how connections reach the transparent listener [default: redirect]
+    #[arg(long, env = "BEGONIA_TRANSPARENT_MODE")]
+    transparent_mode: Option<TransparentMode>,
+
+    /// [default: cluster.local]
+    #[arg(long, env = "BEGONIA_CLUSTER_DOMAIN")]
+    cluster_domain: Option<String>,
+
+    /// namespace for names which don't specify one [default: the pod's own namespace]
+    #[arg(long, env = "BEGONIA_DEFAULT_NAMESPACE")]
+    default_namespace: Option<String>,
+
+    /// namespace of the cluster dns service [default: kube-system]
+    #[arg(long, env = "BEGONIA_DNS_NAMESPACE")]
+    dns_namespace: Option<String>,
+
+    /// name of the cluster dns service [default: kube-dns]
+    #[arg(long, env = "BEGONIA_DNS_SERVICE")]
+    dns_service: Option<String>,
+
+    /// [default: 53]
+    #[arg(long, env = "BEGONIA_DNS_PORT")]
+    dns_port: Option<u16>,
+
+    /// number of dns responses to cache
+    #[arg(long, env = "BEGONIA_DNS_CACHE_SIZE")]
+    dns_cache_size: Option<usize>,
+
+    /// cache dns answers for at least this long, regardless of their ttl
+    #[arg(long, env = "BEGONIA_DNS_MIN_TTL_SECS")]
+    dns_min_ttl_secs: Option<u64>,
+
+    /// cache dns answers for at most this long, regardless of their ttl
+    #[arg(long, env = "BEGONIA_DNS_MAX_TTL_SECS")]
+    dns_max_ttl_secs: Option<u64>,
+
+    /// cache "no such name" for at most this long; zero disables negative caching
+    #[arg(long, env = "BEGONIA_DNS_NEGATIVE_MAX_TTL_SECS")]
+    dns_negative_max_ttl_secs: Option<u64>,
+
+    /// watch services, endpoints and pods, instead of fetching them per request [default: true]
+    #[arg(long, env = "BEGONIA_WATCH_CACHE")]
+    watch_cache: Option<bool>,
+
+    /// refuse clients which don't present a service account token [default: false]
+    #[arg(long, env = "BEGONIA_REQUIRE_AUTH")]
+    require_auth: Option<bool>,
+
+    /// refuse loopback, link-local, cloud metadata, node and our own addresses [default: true]
+    #[arg(long, env = "BEGONIA_BLOCK_INTERNAL")]
+    block_internal: Option<bool>,
+
+    /// how to reach the resolved pods [default: direct]
+    #[arg(long, env = "BEGONIA_EGRESS")]
+    egress: Option<EgressMode>,
+
+    /// which of a target's addresses to try first [default: random]
+    #[arg(long, env = "BEGONIA_ENDPOINT_SELECTION")]
+    endpoint_selection: Option<Selection>,
+
+    /// give up on an address after this long, and try the next [default: 3000]
+    #[arg(long, env = "BEGONIA_CONNECT_ATTEMPT_TIMEOUT_MS")]
+    connect_attempt_timeout_ms: Option<u64>,
+
+    /// start trying the next address if the last hasn't connected after this long [default: 250]
+    #[arg(long, env = "BEGONIA_CONNECT_STAGGER_MS")]
+    connect_stagger_ms: Option<u64>,
+
+    /// give up on clients which haven't finished the handshake after this long [default: 10]
+    #[arg(long, env = "BEGONIA_HANDSHAKE_TIMEOUT_SECS")]
+    handshake_timeout_secs: Option<u64>,
+
+    /// give up resolving a target after this long [default: 10]
+    #[arg(long, env = "BEGONIA_RESOLVE_TIMEOUT_SECS")]
+    resolve_timeout_secs: Option<u64>,
+
+    /// give up connecting to a target, across all its addresses, after this long [default: 30]
+    #[arg(long, env = "BEGONIA_CONNECT_TIMEOUT_SECS")]
+    connect_timeout_secs: Option<u64>,
+
+    /// close tunnels with no traffic either way for this long; zero disables [default: 3600]
+    #[arg(long, env = "BEGONIA_IDLE_TIMEOUT_SECS")]
+    idle_timeout_secs: Option<u64>,
+
+    /// close tunnels which have been open this long; zero disables [default: 0]
+    #[arg(long, env = "BEGONIA_MAX_TUNNEL_LIFETIME_SECS")]
+    max_tunnel_lifetime_secs: Option<u64>,
+
+    /// on SIGTERM, let open connections run for up to this long before exiting [default: 25]
+    #[arg(long, env = "BEGONIA_DRAIN_TIMEOUT_SECS")]
+    drain_timeout_secs: Option<u64>,
+}
+
+macro_rules! override_from {
+    ($config:ident, $args:ident, $($field:ident),* $(,)?) => {
+        $(if let Some(v) = $args.$field {
+            $config.$field = v;
+        })*
+    };
+}
+
+macro_rules! override_optional_from {
+    ($config:ident, $args:ident, $($field:ident),* $(,)?) => {
+        $(if $args.$field.is_some() {
+            $config.$field = $args.$field;
+        })*
+    };
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| anyhow!("reading config {:?}", path))?;
+                toml::from_str(&text).with_context(|| anyhow!("parsing config {:?}", path))?
+            }
+            None => Config::default(),
+        };
+
+        override_from!(
+            config,
+            args,
+            listen,
+            admin_listen,
+            admin_users,
+            legacy_health,
+            transparent_mode,
+            cluster_domain,
+            dns_namespace,
+            dns_service,
+            dns_port,
+            watch_cache,
+            require_auth,
+            block_internal,
+            egress,
+            endpoint_selection,
+            connect_attempt_timeout_ms,
+            connect_stagger_ms,
+            handshake_timeout_secs,
+            resolve_timeout_secs,
+            connect_timeout_secs,
+            idle_timeout_secs,
+            max_tunnel_lifetime_secs,
+            drain_timeout_secs,
+        );
+        override_optional_from!(
+            config,
+            args,
+            default_namespace,
+            transparent_listen,
+            dns_cache_size,
+            dns_min_ttl_secs,
+            dns_max_ttl_secs,
+            dns_negative_max_ttl_secs,
+        );
+
+        Ok(config)
+    }
+
+    pub fn connect_strategy(&self) -> Strategy {
+        Strategy::new(
+            self.endpoint_selection,
+            Duration::from_millis(self.connect_attempt_timeout_ms),
+            Duration::from_millis(self.connect_stagger_ms),
+        )
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        let unless_zero = |secs| Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+        Timeouts {
+            handshake: Duration::from_secs(self.handshake_timeout_secs),
+            resolve: Duration::from_secs(self.resolve_timeout_secs),
+            connect: Duration::from_secs(self.connect_timeout_secs),
+            idle: unless_zero(self.idle_timeout_secs),
+            lifetime: unless_zero(self.max_tunnel_lifetime_secs),
+        }
+    }
+
+    pub fn dns_cache(&self) -> DnsCacheOptions {
+        DnsCacheOptions {
+            cache_size: self.dns_cache_size,
+            positive_min_ttl: self.dns_min_ttl_secs.map(Duration::from_secs),
+            positive_max_ttl: self.dns_max_ttl_secs.map(Duration::from_secs),
+            negative_max_ttl: self.dns_negative_max_ttl_secs.map(Duration::from_secs),
+        }
+    }
+}
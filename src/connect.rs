@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::debug;
+use rand::seq::SliceRandom;
+
+use crate::config::Selection;
+
+/// Forget where round robin was up to once there are this many targets.
+const MAX_ROTATIONS: usize = 4096;
+
+/// How to pick between, and try, the addresses a name resolved to: in the order chosen by
+/// the `Selection`, starting a new attempt whenever one fails or the last has been going
+/// for `stagger`, like RFC 8305 "happy eyeballs".
+#[derive(Clone)]
+pub struct Strategy {
+    selection: Selection,
+    attempt_timeout: Duration,
+    stagger: Duration,
+    /// for round robin, where each set of addresses is up to
+    next: Arc<Mutex<HashMap<Vec<SocketAddr>, usize>>>,
+    active: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+}
+
+/// Counts towards the address's connections until it's dropped.
+pub struct Lease {
+    addr: SocketAddr,
+    active: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().expect("poisoned");
+        if let Some(count) = active.get_mut(&self.addr) {
+            *count -= 1;
+            if 0 == *count {
+                active.remove(&self.addr);
+            }
+        }
+    }
+}
+
+impl Strategy {
+    pub fn new(selection: Selection, attempt_timeout: Duration, stagger: Duration) -> Strategy {
+        Strategy {
+            selection,
+            attempt_timeout,
+            stagger,
+            next: Arc::default(),
+            active: Arc::default(),
+        }
+    }
+
+    fn lease(&self, addr: SocketAddr) -> Lease {
+        *self
+            .active
+            .lock()
+            .expect("poisoned")
+            .entry(addr)
+            .or_default() += 1;
+        Lease {
+            addr,
+            active: self.active.clone(),
+        }
+    }
+
+    /// The order to try the addresses in.
+    fn order(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let mut addrs = addrs.to_vec();
+        match self.selection {
+            Selection::Random => addrs.shuffle(&mut rand::thread_rng()),
+            Selection::RoundRobin => {
+                // sorted, so the rotation doesn't depend on what order the resolver used
+                addrs.sort();
+                let mut next = self.next.lock().expect("poisoned");
+                if next.len() >= MAX_ROTATIONS && !next.contains_key(&addrs) {
+                    // targets come and go; starting them all again is harmless
+                    next.clear();
+                }
+                let count = next.entry(addrs.clone()).or_default();
+                let by = *count % addrs.len().max(1);
+                *count = count.wrapping_add(1);
+                addrs.rotate_left(by);
+            }
+            Selection::LeastConnections => {
+                // shuffled first, so ties are broken randomly
+                addrs.shuffle(&mut rand::thread_rng());
+                let active = self.active.lock().expect("poisoned");
+                addrs.sort_by_key(|addr| active.get(addr).copied().unwrap_or(0));
+            }
+        }
+        interleave(addrs)
+    }
+
+    /// Try the addresses until one works, returning the last error if none do.
+    pub async fn race<T, F, Fut>(
+        &self,
+        addrs: &[SocketAddr],
+        attempt: F,
+    ) -> io::Result<(SocketAddr, T, Lease)>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let timeout = self.attempt_timeout;
+        let start = |addr: SocketAddr| {
+            let attempt = attempt(addr);
+            async move {
+                let result = match tokio::time::timeout(timeout, attempt).await {
+                    Ok(result) => result,
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no response from {} after {:?}", addr, timeout),
+                    )),
+                };
+                (addr, result)
+            }
+        };
+
+        let mut pending = self.order(addrs).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    Some(addr) => attempts.push(start(addr)),
+                    None => {
+                        return Err(last_err.unwrap_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "no addresses to connect to",
+                            )
+                        }))
+                    }
+                }
+            }
+
+            tokio::select! {
+                Some((addr, result)) = attempts.next() => match result {
+                    Ok(connected) => return Ok((addr, connected, self.lease(addr))),
+                    Err(err) => {
+                        debug!("connecting to {}: {:?}", addr, err);
+                        last_err = Some(err);
+                        if let Some(addr) = pending.next() {
+                            attempts.push(start(addr));
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(self.stagger), if pending.len() > 0 => {
+                    let addr = pending.next().expect("checked");
+                    debug!("starting another attempt, on {}", addr);
+                    attempts.push(start(addr));
+                }
+            }
+        }
+    }
+}
+
+/// Alternate between address families, starting with whichever came first.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (mut first, mut second): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut out = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    fn strategy(selection: Selection) -> Strategy {
+        Strategy::new(
+            selection,
+            Duration::from_secs(1),
+            Duration::from_millis(250),
+        )
+    }
+
+    #[test]
+    fn round_robin_rotates_each_target_separately() {
+        let strategy = strategy(Selection::RoundRobin);
+        let a = addrs(&["10.0.0.1:80", "10.0.0.2:80"]);
+        let b = addrs(&["10.0.1.1:80", "10.0.1.2:80"]);
+        let firsts: Vec<SocketAddr> = (0..4)
+            .flat_map(|_| [strategy.order(&a)[0], strategy.order(&b)[0]])
+            .collect();
+        assert_eq!(
+            addrs(&[
+                "10.0.0.1:80",
+                "10.0.1.1:80",
+                "10.0.0.2:80",
+                "10.0.1.2:80",
+                "10.0.0.1:80",
+                "10.0.1.1:80",
+                "10.0.0.2:80",
+                "10.0.1.2:80",
+            ]),
+            firsts
+        );
+    }
+
+    #[test]
+    fn round_robin_ignores_the_resolved_order() {
+        let strategy = strategy(Selection::RoundRobin);
+        let first = strategy.order(&addrs(&["10.0.0.2:80", "10.0.0.1:80"]));
+        let second = strategy.order(&addrs(&["10.0.0.1:80", "10.0.0.2:80"]));
+        assert_eq!(addrs(&["10.0.0.1:80", "10.0.0.2:80"]), first);
+        assert_eq!(addrs(&["10.0.0.2:80", "10.0.0.1:80"]), second);
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_addresses() {
+        let strategy = strategy(Selection::LeastConnections);
+        let both = addrs(&["10.0.0.1:80", "10.0.0.2:80"]);
+        let _lease = strategy.lease(both[0]);
+        assert_eq!(both[1], strategy.order(&both)[0]);
+    }
+
+    #[test]
+    fn interleave_alternates_families_starting_with_the_first() {
+        assert_eq!(
+            addrs(&[
+                "[fd00::1]:80",
+                "10.0.0.1:80",
+                "[fd00::2]:80",
+                "10.0.0.2:80",
+                "10.0.0.3:80"
+            ]),
+            interleave(addrs(&[
+                "[fd00::1]:80",
+                "[fd00::2]:80",
+                "10.0.0.1:80",
+                "10.0.0.2:80",
+                "10.0.0.3:80",
+            ]))
+        );
+        assert_eq!(
+            addrs(&["10.0.0.1:80", "10.0.0.2:80"]),
+            interleave(addrs(&["10.0.0.1:80", "10.0.0.2:80"]))
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use hickory_resolver::TokioAsyncResolver;
+use k8s_openapi::api::core::v1::Endpoints;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::NamespaceResourceScope;
+use kube::api::ListParams;
+use kube::runtime::watcher;
+use kube::{Api, Client};
+use log::{info, warn};
+
+use crate::cache::RETRY_DELAY;
+use crate::endpoints;
+use crate::k8s::{find_dns, find_dns_resolv_conf, find_dns_service, DnsService};
+use crate::resolve::{kube_dns_resolver, DnsCacheOptions};
+
+struct Current {
+    servers: Vec<IpAddr>,
+    resolver: TokioAsyncResolver,
+}
+
+/// The resolver for kube-dns, rebuilt whenever the set of dns pods changes.
+#[derive(Clone)]
+pub struct KubeDns {
+    current: Arc<RwLock<Current>>,
+    service: DnsService,
+    cache: DnsCacheOptions,
+}
+
+impl KubeDns {
+    /// Find the dns servers, and keep watching them for changes.
+    pub async fn start(client: Client, service: DnsService, cache: DnsCacheOptions) -> KubeDns {
+        let servers = discover(&client, &service).await;
+        info!("found {}: {:?}", service.name, servers);
+        let dns = KubeDns {
+            current: Arc::new(RwLock::new(Current {
+                resolver: kube_dns_resolver(&servers, service.port, &cache),
+                servers,
+            })),
+            service,
+            cache,
+        };
+
+        let ns = &dns.service.namespace;
+        let changes = if endpoints::slices_supported(&client).await {
+            watch_changes::<EndpointSlice>(
+                &client,
+                ns,
+                ListParams::default()
+                    .labels(&format!("kubernetes.io/service-name={}", dns.service.name)),
+            )
+        } else {
+            watch_changes::<Endpoints>(
+                &client,
+                ns,
+                ListParams::default().fields(&format!("metadata.name={}", dns.service.name)),
+            )
+        };
+
+        let watching = dns.clone();
+        tokio::spawn(async move {
+            let mut changes = changes;
+            while changes.next().await.is_some() {
+                watching.update(discover(&client, &watching.service).await);
+            }
+        });
+
+        dns
+    }
+
+    pub fn resolver(&self) -> TokioAsyncResolver {
+        self.current.read().expect("poisoned").resolver.clone()
+    }
+
+    fn update(&self, mut servers: Vec<IpAddr>) {
+        servers.sort();
+        let mut current = self.current.write().expect("poisoned");
+        let mut previous = current.servers.clone();
+        previous.sort();
+        if previous == servers {
+            return;
+        }
+        info!(
+            "{} changed: {:?} -> {:?}",
+            self.service.name, current.servers, servers
+        );
+        current.resolver = kube_dns_resolver(&servers, self.service.port, &self.cache);
+        current.servers = servers;
+    }
+}
+
+/// The dns pods, or failing that the service's ip, or failing that whatever we were given.
+async fn discover(client: &Client, service: &DnsService) -> Vec<IpAddr> {
+    match find_dns(client.clone(), service).await {
+        Ok(servers) if !servers.is_empty() => return servers,
+        Ok(_) => warn!("{} has no endpoints", service.name),
+        Err(err) => warn!("finding {} endpoints: {:?}", service.name, err),
+    }
+    match find_dns_service(client.clone(), service).await {
+        Ok(servers) if !servers.is_empty() => return servers,
+        Ok(_) => warn!("{} service has no cluster ip", service.name),
+        Err(err) => warn!("finding {} service: {:?}", service.name, err),
+    }
+    match find_dns_resolv_conf() {
+        Ok(servers) => servers,
+        Err(err) => {
+            warn!("no dns servers at all: {:?}", err);
+            Vec::new()
+        }
+    }
+}
+
+/// A tick for every change to the matching objects.
+fn watch_changes<K>(client: &Client, ns: &str, lp: ListParams) -> BoxStream<'static, ()>
+where
+    K: kube::Resource<DynamicType = (), Scope = NamespaceResourceScope>
+        + Clone
+        + serde::de::DeserializeOwned
+        + std::fmt::Debug
+        + Send
+        + 'static,
+{
+    watcher(Api::<K>::namespaced(client.clone(), ns), lp)
+        .then(|event| async move {
+            if let Err(err) = event {
+                warn!("watching dns: {:?}", err);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        })
+        .boxed()
+}
@@ -0,0 +1,112 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use k8s_openapi::api::core::v1::Pod;
+use kube::{Api, Client};
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::cache::Cache;
+use crate::connect::{Lease, Strategy};
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// How a resolved target is actually reached.
+#[derive(Clone)]
+pub enum Egress {
+    /// dial the address; needs to be running in the cluster
+    Direct,
+    /// find the pod with the address, and tunnel through the apiserver's `pods/portforward`
+    ApiServer { client: Client, cache: Cache },
+}
+
+pub struct Connected {
+    pub stream: Box<dyn Stream>,
+    /// which of the candidate addresses we reached
+    pub addr: SocketAddr,
+    /// our end, for protocols which report it
+    pub bound: SocketAddr,
+    /// held for as long as the connection is in use
+    pub lease: Lease,
+}
+
+impl Egress {
+    pub async fn connect(
+        &self,
+        strategy: &Strategy,
+        addrs: &[SocketAddr],
+    ) -> io::Result<Connected> {
+        let (addr, (stream, bound), lease) =
+            strategy.race(addrs, |addr| self.attempt(addr)).await?;
+        Ok(Connected {
+            stream,
+            addr,
+            bound,
+            lease,
+        })
+    }
+
+    async fn attempt(&self, addr: SocketAddr) -> io::Result<(Box<dyn Stream>, SocketAddr)> {
+        match self {
+            Egress::Direct => {
+                let stream = TcpStream::connect(addr).await?;
+                let bound = stream.local_addr()?;
+                Ok((Box::new(stream), bound))
+            }
+            Egress::ApiServer { client, cache } => {
+                let stream = port_forward(client, cache, addr).await?;
+                Ok((
+                    stream,
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                ))
+            }
+        }
+    }
+}
+
+async fn port_forward(
+    client: &Client,
+    cache: &Cache,
+    addr: SocketAddr,
+) -> io::Result<Box<dyn Stream>> {
+    let (ns, name) = pod_for_ip(client, cache, addr.ip()).await?;
+    debug!("port-forwarding to {}/{}:{}", ns, name, addr.port());
+
+    let mut forwarder = Api::<Pod>::namespaced(client.clone(), &ns)
+        .portforward(&name, &[addr.port()])
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::ConnectionRefused, err))?;
+    let stream = forwarder
+        .take_stream(addr.port())
+        .expect("requested this port");
+
+    // the apiserver only reports failures (e.g. nothing listening) out of band
+    if let Some(error) = forwarder.take_error(addr.port()) {
+        let target = format!("{}/{}:{}", ns, name, addr.port());
+        tokio::spawn(async move {
+            if let Some(msg) = error.await {
+                warn!("port-forward to {} failed: {}", target, msg);
+            }
+        });
+    }
+
+    Ok(Box::new(stream))
+}
+
+async fn pod_for_ip(client: &Client, cache: &Cache, ip: IpAddr) -> io::Result<(String, String)> {
+    let pods = cache
+        .pods_with_ip(client, None, ip)
+        .await
+        .map_err(io::Error::other)?;
+
+    pods.iter()
+        .find_map(|pod| Some((pod.metadata.namespace.clone()?, pod.metadata.name.clone()?)))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                format!("no pod has the ip {}", ip),
+            )
+        })
+}
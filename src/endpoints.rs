@@ -0,0 +1,255 @@
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use k8s_openapi::api::core::v1::Endpoints;
+use k8s_openapi::api::discovery::v1::{EndpointConditions, EndpointSlice};
+use kube::api::ListParams;
+use kube::Api;
+use kube::Client;
+use log::debug;
+
+/// Addresses which share a set of ports: an `Endpoints` subset, or an `EndpointSlice`.
+#[derive(Debug, Clone)]
+pub struct EndpointGroup {
+    pub addresses: Vec<IpAddr>,
+    pub ports: Vec<GroupPort>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GroupPort {
+    pub name: Option<String>,
+    pub port: u16,
+}
+
+/// Whether the cluster serves `discovery.k8s.io/v1`, i.e. is at least 1.21.
+pub async fn slices_supported(client: &Client) -> bool {
+    let probe = Api::<EndpointSlice>::all(client.clone())
+        .list(&ListParams::default().limit(1))
+        .await;
+    !matches!(probe, Err(kube::Error::Api(resp)) if resp.code == 404)
+}
+
+/// The usable endpoints of a service, from its `EndpointSlice`s if the cluster has them,
+/// otherwise from the legacy `Endpoints` object.
+pub async fn fetch(client: &Client, ns: &str, service: &str) -> Result<Vec<EndpointGroup>> {
+    let slices = Api::<EndpointSlice>::namespaced(client.clone(), ns)
+        .list(&ListParams::default().labels(&format!("kubernetes.io/service-name={}", service)))
+        .await;
+    match slices {
+        Ok(slices) if !slices.items.is_empty() => from_slices(&slices.items),
+        Ok(_) => {
+            debug!("no endpointslices for {}/{}, trying endpoints", ns, service);
+            fetch_endpoints(client, ns, service).await
+        }
+        Err(kube::Error::Api(resp)) if resp.code == 404 => {
+            debug!("endpointslices unavailable, trying endpoints");
+            fetch_endpoints(client, ns, service).await
+        }
+        Err(err) => Err(err).with_context(|| anyhow!("listing endpointslices")),
+    }
+}
+
+async fn fetch_endpoints(client: &Client, ns: &str, service: &str) -> Result<Vec<EndpointGroup>> {
+    let endpoints = Api::<Endpoints>::namespaced(client.clone(), ns)
+        .get(service)
+        .await
+        .with_context(|| anyhow!("getting endpoints {}/{}", ns, service))?;
+    from_endpoints(&endpoints)
+}
+
+pub fn from_endpoints(endpoints: &Endpoints) -> Result<Vec<EndpointGroup>> {
+    endpoints
+        .subsets
+        .iter()
+        .flatten()
+        .map(|subset| {
+            Ok(EndpointGroup {
+                addresses: subset
+                    .addresses
+                    .iter()
+                    .flatten()
+                    .map(|address| parse_ip(&address.ip))
+                    .collect::<Result<_>>()?,
+                ports: subset
+                    .ports
+                    .iter()
+                    .flatten()
+                    .flat_map(|p| group_port(p.name.clone(), Some(p.port)))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Merge slices, using only `ready` endpoints unless there are none, in which case
+/// fall back to those which are still `serving` (e.g. `terminating` during a rollout).
+pub fn from_slices(slices: &[impl Borrow<EndpointSlice>]) -> Result<Vec<EndpointGroup>> {
+    // "FQDN" slices can't be dialled without another lookup
+    let slices: Vec<&EndpointSlice> = slices
+        .iter()
+        .map(|slice| slice.borrow())
+        .filter(|slice| slice.address_type == "IPv4" || slice.address_type == "IPv6")
+        .collect();
+    let any_ready = slices
+        .iter()
+        .flat_map(|slice| &slice.endpoints)
+        .any(|endpoint| is_ready(endpoint.conditions.as_ref()));
+
+    let mut groups = Vec::with_capacity(slices.len());
+    for slice in slices {
+        let mut addresses = Vec::with_capacity(slice.endpoints.len());
+        for endpoint in &slice.endpoints {
+            let conditions = endpoint.conditions.as_ref();
+            let usable = if any_ready {
+                is_ready(conditions)
+            } else {
+                is_serving(conditions)
+            };
+            if !usable {
+                continue;
+            }
+            for address in &endpoint.addresses {
+                addresses.push(parse_ip(address)?);
+            }
+        }
+
+        groups.push(EndpointGroup {
+            addresses,
+            ports: slice
+                .ports
+                .iter()
+                .flatten()
+                .flat_map(|p| group_port(p.name.clone(), p.port))
+                .collect(),
+        });
+    }
+    Ok(groups)
+}
+
+// unset conditions should be interpreted as true, per the api docs
+fn is_ready(conditions: Option<&EndpointConditions>) -> bool {
+    conditions.and_then(|c| c.ready).unwrap_or(true)
+}
+
+fn is_serving(conditions: Option<&EndpointConditions>) -> bool {
+    conditions
+        .and_then(|c| c.serving.or(c.ready))
+        .unwrap_or(true)
+}
+
+fn group_port(name: Option<String>, port: Option<i32>) -> Option<GroupPort> {
+    // normalise the empty name, which endpoints and slices represent differently
+    let name = name.filter(|name| !name.is_empty());
+    let port = u16::try_from(port?).ok()?;
+    Some(GroupPort { name, port })
+}
+
+fn parse_ip(ip: &str) -> Result<IpAddr> {
+    IpAddr::from_str(ip).with_context(|| anyhow!("parsing {:?}", ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::discovery::v1::{Endpoint, EndpointPort};
+
+    use super::*;
+
+    /// `(address, ready, serving)`, with `None` for unset conditions
+    fn slice(
+        address_type: &str,
+        endpoints: &[(&str, Option<bool>, Option<bool>)],
+        port: (&str, i32),
+    ) -> EndpointSlice {
+        EndpointSlice {
+            address_type: address_type.to_string(),
+            endpoints: endpoints
+                .iter()
+                .map(|&(address, ready, serving)| Endpoint {
+                    addresses: vec![address.to_string()],
+                    conditions: Some(EndpointConditions {
+                        ready,
+                        serving,
+                        terminating: serving.map(|_| ready != Some(true)),
+                    }),
+                    ..Endpoint::default()
+                })
+                .collect(),
+            ports: Some(vec![EndpointPort {
+                name: Some(port.0.to_string()),
+                port: Some(port.1),
+                ..EndpointPort::default()
+            }]),
+            ..EndpointSlice::default()
+        }
+    }
+
+    fn addresses(groups: &[EndpointGroup]) -> Vec<String> {
+        groups
+            .iter()
+            .flat_map(|group| &group.addresses)
+            .map(|ip| ip.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn only_ready_endpoints_when_there_are_any() {
+        let slices = [
+            slice(
+                "IPv4",
+                &[
+                    ("10.0.0.1", Some(true), Some(true)),
+                    ("10.0.0.2", Some(false), Some(true)),
+                    ("10.0.0.3", None, None),
+                ],
+                ("http", 8080),
+            ),
+            slice(
+                "IPv6",
+                &[
+                    ("fd00::1", Some(false), Some(false)),
+                    ("fd00::2", Some(true), None),
+                ],
+                ("", 8080),
+            ),
+        ];
+        let groups = from_slices(&slices).unwrap();
+        assert_eq!(vec!["10.0.0.1", "10.0.0.3", "fd00::2"], addresses(&groups));
+        assert_eq!(Some("http"), groups[0].ports[0].name.as_deref());
+        assert_eq!(None, groups[1].ports[0].name);
+    }
+
+    #[test]
+    fn serving_endpoints_when_none_are_ready() {
+        let slices = [slice(
+            "IPv4",
+            &[
+                ("10.0.0.1", Some(false), Some(true)),
+                ("10.0.0.2", Some(false), Some(false)),
+                ("10.0.0.3", Some(false), None),
+            ],
+            ("http", 8080),
+        )];
+        assert_eq!(vec!["10.0.0.1"], addresses(&from_slices(&slices).unwrap()));
+    }
+
+    #[test]
+    fn fqdn_slices_are_skipped() {
+        let slices = [
+            slice("FQDN", &[("example.com", Some(true), None)], ("http", 80)),
+            slice(
+                "IPv4",
+                &[("10.0.0.1", Some(false), Some(true))],
+                ("http", 80),
+            ),
+        ];
+        let groups = from_slices(&slices).unwrap();
+        assert_eq!(1, groups.len());
+        // the ready fqdn endpoint doesn't stop the fallback to serving ones
+        assert_eq!(vec!["10.0.0.1"], addresses(&groups));
+    }
+}
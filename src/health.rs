@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::resolve;
+use crate::resolve::ResolveCtx;
+use crate::shutdown::Shutdown;
+
+/// How long check results are reused for, so probes can't hammer the apiserver.
+const CACHE_FOR: Duration = Duration::from_secs(5);
+
+/// How long each check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+type Checks = BTreeMap<&'static str, Check>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The body of `/readyz`: whether we're ready, and why not.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub checks: Checks,
+}
+
+/// Whether we can do anything useful for a client: reach the apiserver, look names
+/// up in the cluster, and aren't on our way out.
+#[derive(Clone)]
+pub struct Health {
+    resolve: ResolveCtx,
+    shutdown: Shutdown,
+    cached: Arc<Mutex<Option<(Instant, Checks)>>>,
+}
+
+impl Health {
+    pub fn new(resolve: ResolveCtx, shutdown: Shutdown) -> Health {
+        Health {
+            resolve,
+            shutdown,
+            cached: Arc::default(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.shutdown.is_draining()
+    }
+
+    pub async fn readiness(&self) -> Report {
+        let mut checks = self.cached_checks().await;
+        checks.insert(
+            "draining",
+            Check {
+                ok: !self.shutdown.is_draining(),
+                error: None,
+            },
+        );
+        Report {
+            ok: checks.values().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    /// held across the checks, so concurrent probes wait for one run instead of starting more
+    async fn cached_checks(&self) -> Checks {
+        let mut cached = self.cached.lock().await;
+        if let Some((at, checks)) = cached.as_ref() {
+            if at.elapsed() < CACHE_FOR {
+                return checks.clone();
+            }
+        }
+        let (apiserver, dns) =
+            futures::join!(check(self.check_apiserver()), check(self.check_dns()));
+        let mut checks = BTreeMap::new();
+        checks.insert("apiserver", apiserver);
+        checks.insert("dns", dns);
+        *cached = Some((Instant::now(), checks.clone()));
+        checks
+    }
+
+    async fn check_apiserver(&self) -> Result<()> {
+        self.resolve.client.apiserver_version().await?;
+        Ok(())
+    }
+
+    /// the apiserver's own service always exists, so any cluster should be able to find it
+    async fn check_dns(&self) -> Result<()> {
+        let dns = match &self.resolve.dns {
+            Some(dns) => dns,
+            // tunnelling through the apiserver, which the other check covers
+            None => return Ok(()),
+        };
+        let hostname = format!("kubernetes.default.svc.{}.", self.resolve.cluster_local);
+        let found = resolve::resolve_against_kube_dns(&self.resolve, dns, &hostname).await?;
+        if found.is_empty() {
+            return Err(anyhow!("no addresses for {:?}", hostname));
+        }
+        Ok(())
+    }
+}
+
+async fn check(fut: impl std::future::Future<Output = Result<()>>) -> Check {
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("no answer after {:?}", CHECK_TIMEOUT)),
+    };
+    Check {
+        ok: result.is_ok(),
+        error: result.err().map(|e| format!("{:#}", e)),
+    }
+}
@@ -0,0 +1,861 @@
+use std::cmp::min;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use url::{Host, Position, Url};
+
+use crate::access_log::AccessRecord;
+use crate::auth::Identity;
+use crate::connect::Lease;
+use crate::egress::Stream;
+use crate::metrics::{self, Counted};
+use crate::sessions::Session;
+use crate::timeouts::{Activity, Watched};
+use crate::{requested_namespace, resolve, Refused, Rejection, WorkerCtx};
+
+/// Refuse request and response heads bigger than this.
+const MAX_HEAD: usize = 64 * 1024;
+/// Chunk size lines, and trailers.
+const MAX_LINE: usize = 8 * 1024;
+
+/// Headers which only describe this hop, so aren't passed on.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authorization",
+    "te",
+    "upgrade",
+    "x-begonia-namespace",
+];
+
+/// A complete response, with a short explanation, after which the connection is closed.
+pub fn error_response(rejection: Rejection) -> Vec<u8> {
+    let (status, body) = match rejection {
+        Rejection::General => ("502 Bad Gateway", "couldn't reach the destination"),
+        Rejection::Invalid => ("400 Bad Request", "invalid request"),
+        Rejection::Unauthenticated => (
+            "407 Proxy Authentication Required",
+            "a valid service account token is required",
+        ),
+        Rejection::NotFound => ("502 Bad Gateway", "no such host or service"),
+        Rejection::Unavailable => ("503 Service Unavailable", "no ready endpoints"),
+        Rejection::HostUnreachable => ("502 Bad Gateway", "destination host unreachable"),
+        Rejection::NetworkUnreachable => ("502 Bad Gateway", "destination network unreachable"),
+        Rejection::ConnectionRefused => ("502 Bad Gateway", "connection refused by destination"),
+        Rejection::TimedOut => ("504 Gateway Timeout", "timed out reaching destination"),
+        Rejection::Forbidden => ("403 Forbidden", "destination not allowed"),
+    };
+    let challenge = match rejection {
+        Rejection::Unauthenticated => {
+            concat!(
+                "Proxy-Authenticate: Basic realm=\"begonia\"\r\n",
+                "Proxy-Authenticate: Bearer realm=\"begonia\"\r\n",
+            )
+        }
+        _ => "",
+    };
+    format!(
+        concat!(
+            "HTTP/1.1 {}\r\n{}",
+            "Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        ),
+        status,
+        challenge,
+        body.len() + 1,
+        body
+    )
+    .into_bytes()
+}
+
+/// The token from a `Proxy-Authorization` header, either directly as `Bearer`, or as the
+/// password of `Basic`, in which case the username is ignored.
+pub fn credentials(headers: &[httparse::Header]) -> Result<Option<String>> {
+    let value = match header(headers, "proxy-authorization") {
+        Some(value) => std::str::from_utf8(value)?.trim(),
+        None => return Ok(None),
+    };
+    let (scheme, param) = value
+        .split_once(' ')
+        .ok_or(anyhow!("malformed proxy-authorization"))?;
+    let param = param.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Ok(Some(param.to_string()));
+    }
+    if !scheme.eq_ignore_ascii_case("basic") {
+        bail!("unsupported proxy-authorization scheme {:?}", scheme);
+    }
+    let decoded = String::from_utf8(
+        STANDARD
+            .decode(param)
+            .context("decoding basic proxy-authorization")?,
+    )?;
+    let (_, password) = decoded
+        .split_once(':')
+        .ok_or(anyhow!("no password in basic proxy-authorization"))?;
+    Ok(Some(password.to_string()))
+}
+
+/// `host:port`, or `[v6]:port`, as in a `CONNECT`.
+pub fn split_host_port(authority: &str) -> Result<(&str, u16)> {
+    let colon = authority
+        .rfind(':')
+        .ok_or(anyhow!("port required in hostname"))?;
+    let (hostname, port) = authority.split_at(colon);
+    let port = u16::from_str(&port[1..]).with_context(|| anyhow!("invalid port {:?}", port))?;
+    let hostname = hostname
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(hostname);
+    if hostname.is_empty() {
+        bail!("empty hostname");
+    }
+    Ok((hostname, port))
+}
+
+/// Parse a request, with as many headers as it takes; the count is bounded by the length.
+pub fn parse_request<'h, 'b>(
+    headers: &'h mut Vec<httparse::Header<'b>>,
+    buf: &'b [u8],
+) -> Result<(httparse::Request<'h, 'b>, httparse::Status<usize>)> {
+    while let Err(httparse::Error::TooManyHeaders) = httparse::Request::new(headers).parse(buf) {
+        headers.resize(headers.len() * 2, httparse::EMPTY_HEADER);
+    }
+    let mut req = httparse::Request::new(headers);
+    let status = req.parse(buf)?;
+    Ok((req, status))
+}
+
+fn parse_response<'h, 'b>(
+    headers: &'h mut Vec<httparse::Header<'b>>,
+    buf: &'b [u8],
+) -> Result<(httparse::Response<'h, 'b>, httparse::Status<usize>)> {
+    while let Err(httparse::Error::TooManyHeaders) = httparse::Response::new(headers).parse(buf) {
+        headers.resize(headers.len() * 2, httparse::EMPTY_HEADER);
+    }
+    let mut resp = httparse::Response::new(headers);
+    let status = resp.parse(buf)?;
+    Ok((resp, status))
+}
+
+/// The length of the request head at the start of `buf`, once it's all there; like
+/// httparse, this accepts bare `\n` line endings.
+fn request_end(buf: &[u8]) -> Result<Option<usize>> {
+    let mut headers = vec![httparse::EMPTY_HEADER; 16];
+    Ok(match parse_request(&mut headers, buf)?.1 {
+        httparse::Status::Complete(len) => Some(len),
+        httparse::Status::Partial => None,
+    })
+}
+
+fn response_end(buf: &[u8]) -> Result<Option<usize>> {
+    let mut headers = vec![httparse::EMPTY_HEADER; 16];
+    Ok(match parse_response(&mut headers, buf)?.1 {
+        httparse::Status::Complete(len) => Some(len),
+        httparse::Status::Partial => None,
+    })
+}
+
+/// Something we're reading http from, and what's been read but not yet used.
+struct Buffered<R> {
+    inner: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Buffered<R> {
+    fn new(inner: R, buf: Vec<u8>) -> Buffered<R> {
+        Buffered { inner, buf }
+    }
+
+    /// `false` at eof
+    async fn fill(&mut self) -> Result<bool> {
+        let mut chunk = [0; 8 * 1024];
+        let found = self.inner.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..found]);
+        Ok(found != 0)
+    }
+
+    /// The request or status line, and the headers, which `end` finds the length of once
+    /// they're complete; `None` if the stream ended before any of it.
+    async fn head(&mut self, end: fn(&[u8]) -> Result<Option<usize>>) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(end) = end(&self.buf)? {
+                return Ok(Some(self.buf.drain(..end).collect()));
+            }
+            if self.buf.len() > MAX_HEAD {
+                bail!("head longer than {} bytes", MAX_HEAD);
+            }
+            if !self.fill().await? {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                bail!("eof part way through a head");
+            }
+        }
+    }
+
+    /// Up to and including the `\n`.
+    async fn line(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&c| c == b'\n') {
+                return Ok(self.buf.drain(..=end).collect());
+            }
+            if self.buf.len() > MAX_LINE {
+                bail!("line longer than {} bytes", MAX_LINE);
+            }
+            if !self.fill().await? {
+                bail!("eof part way through a line");
+            }
+        }
+    }
+
+    async fn copy_exact<W: AsyncWrite + Unpin>(&mut self, to: &mut W, mut len: u64) -> Result<()> {
+        loop {
+            let available = min(len, self.buf.len() as u64) as usize;
+            to.write_all(&self.buf[..available]).await?;
+            self.buf.drain(..available);
+            len -= available as u64;
+            if 0 == len {
+                return Ok(());
+            }
+            if !self.fill().await? {
+                bail!("eof with {} bytes of body remaining", len);
+            }
+        }
+    }
+
+    /// Pass on a chunked body as-is, including any trailers.
+    async fn copy_chunked<W: AsyncWrite + Unpin>(&mut self, to: &mut W) -> Result<()> {
+        loop {
+            let line = self.line().await?;
+            to.write_all(&line).await?;
+            let size = std::str::from_utf8(&line)?
+                .split(';')
+                .next()
+                .expect("split always yields")
+                .trim();
+            let size = u64::from_str_radix(size, 16)
+                .with_context(|| anyhow!("invalid chunk size {:?}", size))?;
+            if 0 == size {
+                loop {
+                    let trailer = self.line().await?;
+                    to.write_all(&trailer).await?;
+                    if trailer == b"\r\n" || trailer == b"\n" {
+                        return Ok(());
+                    }
+                }
+            }
+            // the data, and its CRLF
+            self.copy_exact(to, size + 2).await?;
+        }
+    }
+
+    async fn copy_to_eof<W: AsyncWrite + Unpin>(&mut self, to: &mut W) -> Result<()> {
+        to.write_all(&self.buf).await?;
+        self.buf.clear();
+        tokio::io::copy(&mut self.inner, to).await?;
+        Ok(())
+    }
+
+    async fn copy_body<W: AsyncWrite + Unpin>(&mut self, to: &mut W, body: Body) -> Result<()> {
+        match body {
+            Body::Empty => Ok(()),
+            Body::Length(len) => self.copy_exact(to, len).await,
+            Body::Chunked => self.copy_chunked(to).await,
+            Body::UntilClose => self.copy_to_eof(to).await,
+        }
+    }
+}
+
+/// How the end of a message's body is found.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Body {
+    Empty,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+pub fn header<'h>(headers: &'h [httparse::Header], name: &str) -> Option<&'h [u8]> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value)
+}
+
+/// Whether a comma-separated header, like `Connection`, contains the token.
+fn has_token(headers: &[httparse::Header], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .flat_map(|h| h.value.split(|&c| c == b','))
+        .any(|v| v.trim_ascii().eq_ignore_ascii_case(token.as_bytes()))
+}
+
+/// Comma-separated values, across every header with the name.
+fn values<'h>(headers: &'h [httparse::Header], name: &str) -> Vec<&'h [u8]> {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .flat_map(|h| h.value.split(|&c| c == b','))
+        .map(|v| v.trim_ascii())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// How the body is delimited, refusing anything another hop might read differently.
+fn framing(headers: &[httparse::Header], otherwise: Body) -> Result<Body> {
+    let codings = values(headers, "transfer-encoding");
+    if let Some((last, rest)) = codings.split_last() {
+        let chunked = |coding: &&[u8]| coding.eq_ignore_ascii_case(b"chunked");
+        if rest.iter().any(chunked) {
+            bail!("chunked applied more than once");
+        }
+        if chunked(last) {
+            // any content-length is ignored, and not forwarded
+            return Ok(Body::Chunked);
+        }
+        // a response is then read until the upstream closes; a request can't be
+        if otherwise == Body::UntilClose {
+            return Ok(Body::UntilClose);
+        }
+        bail!("transfer-encoding doesn't end in chunked");
+    }
+
+    let mut length = None;
+    for value in values(headers, "content-length") {
+        // `from_str` would also take a sign
+        if !value.iter().all(u8::is_ascii_digit) {
+            bail!(
+                "invalid content-length {:?}",
+                String::from_utf8_lossy(value)
+            );
+        }
+        let value = u64::from_str(std::str::from_utf8(value)?).context("content-length")?;
+        if length.is_some_and(|length| length != value) {
+            bail!("conflicting content-lengths");
+        }
+        length = Some(value);
+    }
+    Ok(length.map(Body::Length).unwrap_or(otherwise))
+}
+
+/// The framing we decided on, rather than what we were sent: at most one `Content-Length`,
+/// and never one alongside chunked.
+fn write_framing(out: &mut Vec<u8>, body: Body) {
+    if let Body::Length(len) = body {
+        out.extend_from_slice(format!("Content-Length: {}\r\n", len).as_bytes());
+    }
+}
+
+/// Copy the headers which apply end-to-end, i.e. not those for this hop, or
+/// named by its `Connection`.
+fn write_headers(out: &mut Vec<u8>, headers: &[httparse::Header], skip: &[&str]) {
+    let named: Vec<&[u8]> = headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+        .flat_map(|h| h.value.split(|&c| c == b','))
+        .map(|v| v.trim_ascii())
+        .collect();
+    for h in headers {
+        let hop = HOP_BY_HOP
+            .iter()
+            .chain(skip)
+            .any(|name| h.name.eq_ignore_ascii_case(name))
+            || named
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(h.name.as_bytes()));
+        if hop {
+            continue;
+        }
+        out.extend_from_slice(h.name.as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(h.value);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// What an upstream's responses are read through.
+type UpstreamRead = Counted<Watched<ReadHalf<Box<dyn Stream>>>>;
+
+/// An upstream connection, kept for the next request if it's to the same place.
+struct Upstream {
+    /// host, port and namespace, as the client asked for them
+    target: (String, u16, String),
+    read: Buffered<UpstreamRead>,
+    write: WriteHalf<Box<dyn Stream>>,
+    _lease: Lease,
+}
+
+/// The client's side of a forwarding connection, shared by every request on it.
+struct Peer<'a> {
+    identity: Option<&'a Identity>,
+    activity: &'a Activity,
+    session: &'a Session,
+    /// host, port and namespace the access record is for, once there's a request
+    recording: Option<(String, u16, String)>,
+    /// the session's byte totals when the access record was started
+    since: (u64, u64),
+}
+
+impl Peer<'_> {
+    /// Fill in the record's byte counts, leaving out `pending` bytes read from the
+    /// client which belong to the next record.
+    fn tally(&mut self, record: &mut AccessRecord, pending: u64) {
+        let up = self.session.bytes_up().load(Ordering::Relaxed) - pending;
+        let down = self.session.bytes_down().load(Ordering::Relaxed);
+        record.bytes_up = up - self.since.0;
+        record.bytes_down = down - self.since.1;
+        self.since = (up, down);
+    }
+}
+
+/// Act as a plain forward proxy: read requests for absolute urls, send them on in origin
+/// form, and pass the responses back, for as long as the client keeps the connection open.
+pub async fn forward(
+    ctx: &WorkerCtx,
+    source: TcpStream,
+    head: Vec<u8>,
+    identity: Option<&Identity>,
+    session: &Session,
+    record: &mut AccessRecord,
+) -> Result<()> {
+    let _active = metrics::ActiveTunnel::start();
+    let (read, mut write) = source.into_split();
+    let activity = Activity::new();
+    // read before we got here, so not through the counter
+    metrics::bytes_up().inc_by(head.len() as u64);
+    session
+        .bytes_up()
+        .fetch_add(head.len() as u64, Ordering::Relaxed);
+    let mut client = Buffered::new(
+        Counted::new(activity.watch(read), metrics::bytes_up()).sharing(session.bytes_up()),
+        head,
+    );
+    let mut upstream = None;
+    let mut peer = Peer {
+        identity,
+        activity: &activity,
+        session,
+        recording: None,
+        since: (0, 0),
+    };
+
+    let result = tokio::select! {
+        result = serve(
+            ctx,
+            &mut client,
+            &mut write,
+            &mut upstream,
+            &mut peer,
+            record,
+        ) => result,
+        expired = ctx.timeouts.expire(&activity) => Err(expired.into()),
+        _ = session.terminated() => {
+            Err(anyhow!("connection {} terminated by an operator", session.id()))
+        }
+    };
+
+    peer.tally(record, 0);
+    result
+}
+
+async fn serve<R, W>(
+    ctx: &WorkerCtx,
+    client: &mut Buffered<R>,
+    client_write: &mut W,
+    upstream: &mut Option<Upstream>,
+    peer: &mut Peer<'_>,
+    record: &mut AccessRecord,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let head = match client.head(request_end).await? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+        let mut headers = vec![httparse::EMPTY_HEADER; 32];
+        let (req, _) = parse_request(&mut headers, &head)?;
+        let method = req
+            .method
+            .ok_or(anyhow!("no method on a complete request?"))?;
+        let path = req.path.ok_or(anyhow!("no path on a complete request?"))?;
+        let version = req
+            .version
+            .ok_or(anyhow!("no version on a complete request?"))?;
+        let headers = req.headers;
+
+        let url = Url::parse(path).with_context(|| anyhow!("parsing url {:?}", path))?;
+        if url.scheme() != "http" {
+            client_write
+                .write_all(&error_response(Rejection::Invalid))
+                .await?;
+            bail!("can't forward {:?}; https must use CONNECT", path);
+        }
+        let hostname = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => bail!("no host in {:?}", path),
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        // chosen afresh by each request, like the target
+        let namespace = header(headers, "x-begonia-namespace")
+            .and_then(requested_namespace)
+            .unwrap_or_else(|| ctx.resolve.default_namespace.clone());
+        let target = (hostname, port, namespace);
+
+        // one record per destination, so the log shows everywhere the client reached
+        if peer.recording.as_ref().is_some_and(|t| *t != target) {
+            peer.tally(record, head.len() as u64);
+            std::mem::replace(record, record.next()).finish(None);
+        }
+        let (hostname, port, namespace) = &target;
+        record.host = Some(hostname.clone());
+        record.port = Some(*port);
+        peer.session.retarget(hostname, *port);
+        peer.recording = Some(target.clone());
+
+        let mut resolve_ctx = ctx.resolve.clone();
+        if *namespace != resolve_ctx.default_namespace {
+            if !resolve::is_namespace(namespace) {
+                client_write
+                    .write_all(&error_response(Rejection::Invalid))
+                    .await?;
+                bail!("client requested invalid namespace {:?}", namespace);
+            }
+            resolve_ctx.default_namespace = namespace.clone();
+        }
+
+        let keep_client = if version == 0 {
+            has_token(headers, "connection", "keep-alive")
+                || has_token(headers, "proxy-connection", "keep-alive")
+        } else {
+            !has_token(headers, "connection", "close")
+                && !has_token(headers, "proxy-connection", "close")
+        };
+        let request_body = match framing(headers, Body::Empty) {
+            Ok(body) => body,
+            Err(err) => {
+                client_write
+                    .write_all(&error_response(Rejection::Invalid))
+                    .await?;
+                return Err(err.context("framing request"));
+            }
+        };
+        // answered here, rather than waiting on the upstream to say so
+        let expect_continue = has_token(headers, "expect", "100-continue");
+
+        if upstream.as_ref().is_some_and(|up| up.target != target) {
+            *upstream = None;
+        }
+        let mut out = format!(
+            "{} {} HTTP/1.{}\r\n",
+            method,
+            &url[Position::BeforePath..Position::AfterQuery],
+            version
+        )
+        .into_bytes();
+        // the url's authority wins over whatever `Host` the client sent
+        out.extend_from_slice(
+            format!(
+                "Host: {}\r\n",
+                &url[Position::BeforeHost..Position::AfterPort]
+            )
+            .as_bytes(),
+        );
+        let mut skip = vec!["host", "content-length"];
+        if expect_continue {
+            skip.push("expect");
+        }
+        write_headers(&mut out, headers, &skip);
+        write_framing(&mut out, request_body);
+        out.extend_from_slice(b"\r\n");
+
+        let mut reused = upstream.is_some();
+        let (response, status, response_version) = loop {
+            let up = match upstream {
+                Some(up) => up,
+                None => {
+                    let (hostname, port, _) = &target;
+                    let hint = format!("HTTP {} to {}", method, hostname);
+                    let resolved = ctx.lookup(resolve_ctx.clone(), hostname, *port).await;
+                    let dest = match ctx.establish(&hint, resolved, peer.identity, record).await {
+                        Ok(dest) => dest,
+                        Err(Refused { rejection, cause }) => {
+                            client_write.write_all(&error_response(rejection)).await?;
+                            return Err(cause);
+                        }
+                    };
+                    let (read, write) = tokio::io::split(dest.stream);
+                    upstream.insert(Upstream {
+                        target: target.clone(),
+                        read: Buffered::new(
+                            Counted::new(peer.activity.watch(read), metrics::bytes_down())
+                                .sharing(peer.session.bytes_down()),
+                            Vec::new(),
+                        ),
+                        write,
+                        _lease: dest.lease,
+                    })
+                }
+            };
+
+            let received = up.read.inner.count();
+            let exchanged = exchange(
+                up,
+                &out,
+                client,
+                client_write,
+                request_body,
+                expect_continue,
+            )
+            .await;
+            let silent = up.read.inner.count() == received;
+            match exchanged {
+                Ok(response) => break response,
+                Err(err) => {
+                    *upstream = None;
+                    // e.g. the server timed out the idle connection as we sent on it
+                    if reused && silent && request_body == Body::Empty && is_idempotent(method) {
+                        debug!(
+                            "retrying {} {} on a new connection: {:#}",
+                            method, path, err
+                        );
+                        reused = false;
+                        continue;
+                    }
+                    client_write
+                        .write_all(&error_response(Rejection::General))
+                        .await?;
+                    return Err(err.context(format!("forwarding {} {}", method, path)));
+                }
+            }
+        };
+        let up = upstream.as_mut().expect("just exchanged");
+
+        let mut headers = vec![httparse::EMPTY_HEADER; 32];
+        let (resp, _) = parse_response(&mut headers, &response)?;
+        let headers = resp.headers;
+        let response_body = if method == "HEAD" || status == 204 || status == 304 {
+            Body::Empty
+        } else {
+            match framing(headers, Body::UntilClose) {
+                Ok(body) => body,
+                Err(err) => {
+                    client_write
+                        .write_all(&error_response(Rejection::General))
+                        .await?;
+                    return Err(err.context("framing response"));
+                }
+            }
+        };
+        let keep_client = keep_client && response_body != Body::UntilClose;
+        let keep_upstream = keep_client
+            && version == 1
+            && response_version == 1
+            && !has_token(headers, "connection", "close");
+
+        let status_line_end = response
+            .iter()
+            .position(|&c| c == b'\n')
+            .expect("complete response");
+        let mut out = response[..=status_line_end].to_vec();
+        if response_body == Body::Empty {
+            // e.g. for a HEAD, the length of what a GET would have returned
+            write_headers(&mut out, headers, &[]);
+        } else {
+            write_headers(&mut out, headers, &["content-length"]);
+            write_framing(&mut out, response_body);
+        }
+        if !keep_client {
+            out.extend_from_slice(b"Connection: close\r\n");
+        } else if version == 0 {
+            out.extend_from_slice(b"Connection: keep-alive\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+        client_write.write_all(&out).await?;
+        up.read.copy_body(client_write, response_body).await?;
+        client_write.flush().await?;
+        debug!("forwarded {} {}: {}", method, path, status);
+
+        if !keep_upstream {
+            *upstream = None;
+        }
+        if !keep_client {
+            client_write.shutdown().await?;
+            return Ok(());
+        }
+    }
+}
+
+/// Send the request, and read the head of the final response, passing on any interim ones.
+async fn exchange<R, W>(
+    up: &mut Upstream,
+    out: &[u8],
+    client: &mut Buffered<R>,
+    client_write: &mut W,
+    request_body: Body,
+    expect_continue: bool,
+) -> Result<(Vec<u8>, u16, u8)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    up.write.write_all(out).await?;
+    if expect_continue {
+        client_write
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await?;
+    }
+    client.copy_body(&mut up.write, request_body).await?;
+    up.write.flush().await?;
+
+    loop {
+        let response = up
+            .read
+            .head(response_end)
+            .await?
+            .ok_or(anyhow!("upstream closed without responding"))?;
+        let mut headers = vec![httparse::EMPTY_HEADER; 32];
+        let (resp, _) = parse_response(&mut headers, &response)?;
+        let status = resp
+            .code
+            .ok_or(anyhow!("no status on a complete response?"))?;
+        let version = resp
+            .version
+            .ok_or(anyhow!("no version on a complete response?"))?;
+        if status == 101 {
+            bail!("upstream switched protocols, which we didn't ask for");
+        }
+        if (100..200).contains(&status) {
+            // interim, e.g. 103 Early Hints; the final response follows
+            client_write.write_all(&response).await?;
+            continue;
+        }
+        return Ok((response, status, version));
+    }
+}
+
+/// Safe to send again, if the connection dropped before it was answered.
+fn is_idempotent(method: &str) -> bool {
+    matches!(
+        method,
+        "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str, otherwise: Body) -> (Result<Body>, Vec<u8>) {
+        let mut headers = vec![httparse::EMPTY_HEADER; 16];
+        let (req, _) = parse_request(&mut headers, head.as_bytes()).expect("valid");
+        let body = framing(req.headers, otherwise);
+        let mut out = Vec::new();
+        if let Ok(body) = body {
+            write_headers(&mut out, req.headers, &["content-length"]);
+            write_framing(&mut out, body);
+        }
+        (body, out)
+    }
+
+    fn request(headers: &str) -> (Result<Body>, Vec<u8>) {
+        parse(
+            &format!("POST http://a/ HTTP/1.1\r\n{}\r\n", headers),
+            Body::Empty,
+        )
+    }
+
+    #[test]
+    fn chunked_wins_over_content_length_which_is_dropped() {
+        let (body, out) = request("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n");
+        assert_eq!(Body::Chunked, body.unwrap());
+        assert_eq!(
+            "Transfer-Encoding: chunked\r\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn chunked_must_be_last_and_only_once() {
+        let (body, _) = request("Transfer-Encoding: gzip, chunked\r\n");
+        assert_eq!(Body::Chunked, body.unwrap());
+        let (body, _) = request("Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n");
+        assert_eq!(Body::Chunked, body.unwrap());
+
+        assert!(request("Transfer-Encoding: chunked, gzip\r\n").0.is_err());
+        assert!(
+            request("Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n")
+                .0
+                .is_err()
+        );
+        assert!(request("Transfer-Encoding: chunked, chunked\r\n")
+            .0
+            .is_err());
+        assert!(request("Transfer-Encoding: gzip\r\nContent-Length: 5\r\n")
+            .0
+            .is_err());
+    }
+
+    #[test]
+    fn responses_without_chunked_last_are_read_until_close() {
+        let (body, _) = parse(
+            "GET http://a/ HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            Body::UntilClose,
+        );
+        assert_eq!(Body::UntilClose, body.unwrap());
+    }
+
+    #[test]
+    fn duplicate_content_lengths_must_agree() {
+        let (body, out) = request("Content-Length: 5\r\nContent-Length: 5\r\n");
+        assert_eq!(Body::Length(5), body.unwrap());
+        assert_eq!("Content-Length: 5\r\n", String::from_utf8(out).unwrap());
+        let (body, _) = request("Content-Length: 5, 5\r\n");
+        assert_eq!(Body::Length(5), body.unwrap());
+
+        assert!(request("Content-Length: 5\r\nContent-Length: 6\r\n")
+            .0
+            .is_err());
+        assert!(request("Content-Length: 5, 6\r\n").0.is_err());
+    }
+
+    #[test]
+    fn content_length_is_only_digits() {
+        assert!(request("Content-Length: +5\r\n").0.is_err());
+        assert!(request("Content-Length: -1\r\n").0.is_err());
+        assert!(request("Content-Length: 0x5\r\n").0.is_err());
+    }
+
+    #[test]
+    fn no_framing_headers_is_the_default() {
+        let (body, out) = request("Accept: */*\r\n");
+        assert_eq!(Body::Empty, body.unwrap());
+        assert_eq!("Accept: */*\r\n", String::from_utf8(out).unwrap());
+    }
+
+    #[tokio::test]
+    async fn heads_may_end_in_bare_newlines() {
+        let stream: &[u8] = b"GET http://a/ HTTP/1.1\nHost: a\n\nGET http://b/ HTTP/1.1\r\n\r\n";
+        let mut client = Buffered::new(stream, Vec::new());
+        let first = client.head(request_end).await.unwrap().unwrap();
+        assert_eq!(b"GET http://a/ HTTP/1.1\nHost: a\n\n", first.as_slice());
+        let second = client.head(request_end).await.unwrap().unwrap();
+        assert_eq!(b"GET http://b/ HTTP/1.1\r\n\r\n", second.as_slice());
+        assert!(client.head(request_end).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,40 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The addresses on our own network interfaces, e.g. the pod's ips.
+pub fn addresses() -> io::Result<Vec<IpAddr>> {
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: on success, `head` is a list we only read, and free once, below
+    if 0 != unsafe { libc::getifaddrs(&mut head) } {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addrs = Vec::new();
+    let mut next = head;
+    while !next.is_null() {
+        // SAFETY: every entry, and any address it has, is valid until `freeifaddrs`
+        let entry = unsafe { &*next };
+        next = entry.ifa_next;
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+        let ip = match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            // e.g. AF_PACKET, for the link itself
+            _ => continue,
+        };
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+
+    unsafe { libc::freeifaddrs(head) };
+    Ok(addrs)
+}
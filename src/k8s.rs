@@ -4,37 +4,66 @@ use std::str::FromStr;
 use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
-use k8s_openapi::api::core::v1::{EndpointPort, Endpoints};
+use k8s_openapi::api::core::v1::Service;
 use kube::Api;
 use kube::Client;
 
-fn has_port(port: i32, ports: &[EndpointPort]) -> bool {
-    ports.into_iter().any(|ep| ep.port == port)
+use crate::endpoints;
+use crate::endpoints::GroupPort;
+
+fn has_port(port: u16, ports: &[GroupPort]) -> bool {
+    ports.iter().any(|ep| ep.port == port)
+}
+
+/// Where the cluster dns lives, typically `kube-system/kube-dns:53`.
+#[derive(Debug, Clone)]
+pub struct DnsService {
+    pub namespace: String,
+    pub name: String,
+    pub port: u16,
 }
 
-pub async fn find_dns(client: Client) -> Result<Vec<IpAddr>> {
-    let endpoints = Api::<Endpoints>::namespaced(client, "kube-system")
-        .get("kube-dns")
+pub async fn find_dns(client: Client, service: &DnsService) -> Result<Vec<IpAddr>> {
+    Ok(endpoints::fetch(&client, &service.namespace, &service.name)
         .await
-        .with_context(|| anyhow!("listing endpoints"))?;
-    endpoints
-        .subsets
-        .unwrap_or_default()
+        .with_context(|| anyhow!("listing endpoints"))?
         .into_iter()
-        .filter(|endpoint| {
-            endpoint
-                .ports
-                .as_ref()
-                .map(|v| has_port(53, v))
-                .unwrap_or(false)
-        })
-        .flat_map(|endpoint| {
-            endpoint
-                .addresses
-                .unwrap_or_default()
-                .into_iter()
-                .map(|address| address.ip)
+        .filter(|group| has_port(service.port, &group.ports))
+        .flat_map(|group| group.addresses)
+        .collect())
+}
+
+/// The dns service's cluster ips, for when its endpoints can't be found.
+pub async fn find_dns_service(client: Client, service: &DnsService) -> Result<Vec<IpAddr>> {
+    let spec = Api::<Service>::namespaced(client, &service.namespace)
+        .get(&service.name)
+        .await
+        .with_context(|| anyhow!("getting service"))?
+        .spec
+        .unwrap_or_default();
+    let mut ips = spec.cluster_ips.unwrap_or_default();
+    if ips.is_empty() {
+        ips.extend(spec.cluster_ip);
+    }
+    Ok(ips
+        .into_iter()
+        // "None" for headless services
+        .flat_map(|ip| IpAddr::from_str(&ip).ok())
+        .collect())
+}
+
+/// The nameservers from `/etc/resolv.conf`, the last resort.
+pub fn find_dns_resolv_conf() -> Result<Vec<IpAddr>> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")
+        .with_context(|| anyhow!("reading /etc/resolv.conf"))?;
+    Ok(conf
+        .lines()
+        .flat_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(ip)) => IpAddr::from_str(ip).ok(),
+                _ => None,
+            }
         })
-        .map(|s| Ok(IpAddr::from_str(&s).with_context(|| anyhow!("parsing {:?}", s))?))
-        .collect()
+        .collect())
 }
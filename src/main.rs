@@ -3,7 +3,8 @@ use std::io;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
-use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::bail;
@@ -13,64 +14,341 @@ use kube::Client;
 use log::debug;
 use log::error;
 use log::info;
+use log::warn;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
-use crate::k8s::find_dns;
+use crate::access_log::AccessRecord;
+use crate::admin::Admin;
+use crate::auth::{Authenticator, Identity};
+use crate::cache::Cache;
+use crate::config::{Config, EgressMode};
+use crate::connect::Strategy;
+use crate::dns::KubeDns;
+use crate::egress::{Connected, Egress};
+use crate::health::Health;
+use crate::k8s::DnsService;
+use crate::policy::Enforcer;
 use crate::resolve::ResolveCtx;
+use crate::sessions::Registry;
+use crate::shutdown::Shutdown;
+use crate::timeouts::{Activity, Expired, Stage, Timeouts};
+use crate::transparent::Interceptor;
 
+mod access_log;
+mod admin;
+mod auth;
+mod cache;
+mod config;
+mod connect;
+mod dns;
+mod egress;
+mod endpoints;
+mod health;
+mod http;
+mod interfaces;
 mod k8s;
+mod metrics;
+mod policy;
 mod resolve;
+mod sessions;
+mod shutdown;
+mod socks5;
+mod timeouts;
+mod transparent;
 
 #[derive(Debug)]
 enum ConnectType {
-    Http { hostname: String, port: u16 },
-    Socks4Ip { ip: Ipv4Addr, port: u16 },
-    Socks4Host { hostname: String, port: u16 },
+    Http {
+        hostname: String,
+        port: u16,
+    },
+    Socks4Ip {
+        ip: Ipv4Addr,
+        port: u16,
+    },
+    Socks4Host {
+        hostname: String,
+        port: u16,
+    },
+    Socks5Ip {
+        addr: SocketAddr,
+    },
+    Socks5Host {
+        hostname: String,
+        port: u16,
+    },
+    /// a plain http request for an absolute url; `head` is everything read so far
+    HttpForward {
+        head: Vec<u8>,
+    },
+    /// redirected to us by the firewall, without the client knowing; there's no handshake
+    Transparent {
+        addr: SocketAddr,
+    },
 
     // not a connect, but we're gonna reply anyway
-    InvalidHttpGet { path: String },
+    InvalidHttpGet {
+        path: String,
+    },
 }
 
-async fn read_initialisation(socket: &mut TcpStream, buf: &mut [u8]) -> Result<ConnectType> {
-    let mut progress = 0;
+/// What the client asked for, and who they proved they were while asking.
+#[derive(Debug)]
+struct Handshake {
+    connect: ConnectType,
+    identity: Option<Identity>,
+    /// the client's choice of namespace for short names, not yet validated
+    namespace: Option<String>,
+    /// anything the client sent after the handshake, which is for the destination
+    leftover: Vec<u8>,
+}
+
+impl From<ConnectType> for Handshake {
+    fn from(connect: ConnectType) -> Handshake {
+        Handshake {
+            connect,
+            identity: None,
+            namespace: None,
+            leftover: Vec::new(),
+        }
+    }
+}
+
+/// Empty means the client didn't choose.
+fn requested_namespace(raw: &[u8]) -> Option<String> {
+    if raw.is_empty() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(raw).to_string())
+}
+
+/// A socks user name only picks the namespace as `ns=<namespace>`; clients often send the
+/// local user's name, which isn't meant for us.
+fn namespace_in_username(raw: &[u8]) -> Option<String> {
+    requested_namespace(raw.strip_prefix(b"ns=")?)
+}
+
+/// Why a connect request couldn't be satisfied, for protocols which can tell the client.
+#[derive(Debug, Copy, Clone)]
+enum Rejection {
+    General,
+    /// the request itself made no sense
+    Invalid,
+    /// the client needs to present a token
+    Unauthenticated,
+    /// the name didn't resolve
+    NotFound,
+    /// the name resolved, but to nothing, e.g. a service with no ready pods
+    Unavailable,
+    HostUnreachable,
+    NetworkUnreachable,
+    ConnectionRefused,
+    TimedOut,
+    /// the policy doesn't allow it
+    Forbidden,
+}
+
+impl Rejection {
+    fn from_io(err: &io::Error) -> Rejection {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => Rejection::ConnectionRefused,
+            io::ErrorKind::HostUnreachable => Rejection::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => Rejection::NetworkUnreachable,
+            io::ErrorKind::TimedOut => Rejection::TimedOut,
+            _ => Rejection::General,
+        }
+    }
+
+    fn from_error(err: &anyhow::Error) -> Rejection {
+        if Expired::is_in(err) {
+            return Rejection::TimedOut;
+        }
+        match err.downcast_ref::<io::Error>() {
+            Some(err) => Rejection::from_io(err),
+            None => Rejection::General,
+        }
+    }
+}
+
+impl ConnectType {
+    /// for labelling metrics
+    fn protocol(&self) -> &'static str {
+        match self {
+            ConnectType::Http { .. } => "http",
+            ConnectType::Socks4Ip { .. } => "socks4_ip",
+            ConnectType::Socks4Host { .. } => "socks4_host",
+            ConnectType::Socks5Ip { .. } => "socks5_ip",
+            ConnectType::Socks5Host { .. } => "socks5_host",
+            ConnectType::HttpForward { .. } => "http_forward",
+            ConnectType::Transparent { .. } => "transparent",
+            ConnectType::InvalidHttpGet { .. } => "invalid_http_get",
+        }
+    }
+
+    /// what the client asked to reach, for the record
+    fn target(&self) -> Option<(String, u16)> {
+        match self {
+            ConnectType::Http { hostname, port }
+            | ConnectType::Socks4Host { hostname, port }
+            | ConnectType::Socks5Host { hostname, port } => Some((hostname.clone(), *port)),
+            ConnectType::Socks4Ip { ip, port } => Some((ip.to_string(), *port)),
+            ConnectType::Socks5Ip { addr } | ConnectType::Transparent { addr } => {
+                Some((addr.ip().to_string(), addr.port()))
+            }
+            // may change with every request
+            ConnectType::HttpForward { .. } | ConnectType::InvalidHttpGet { .. } => None,
+        }
+    }
+
+    fn ok_message(&self, bound: SocketAddr) -> Vec<u8> {
+        match self {
+            ConnectType::Http { .. } => b"HTTP/1.0 200 OK\r\n\r\n".to_vec(),
+            // 5a: OK!, other fields irrelevant for a connect request
+            ConnectType::Socks4Ip { .. } | ConnectType::Socks4Host { .. } => {
+                b"\0\x5a\0\0\0\0\0\0".to_vec()
+            }
+            ConnectType::Socks5Ip { .. } | ConnectType::Socks5Host { .. } => {
+                socks5::reply(socks5::REP_SUCCEEDED, bound)
+            }
+            // the client thinks it's already connected
+            ConnectType::Transparent { .. } => Vec::new(),
+            ConnectType::HttpForward { .. } | ConnectType::InvalidHttpGet { .. } => {
+                unreachable!("not a connect")
+            }
+        }
+    }
+
+    fn rejection_message(&self, rejection: Rejection) -> Option<Vec<u8>> {
+        match self {
+            ConnectType::Http { .. } | ConnectType::HttpForward { .. } => {
+                Some(http::error_response(rejection))
+            }
+            // 5b: generic rejection (no error propagation available)
+            ConnectType::Socks4Ip { .. } | ConnectType::Socks4Host { .. } => {
+                Some(b"\0\x5b\0\0\0\0\0\0".to_vec())
+            }
+            ConnectType::Socks5Ip { .. } | ConnectType::Socks5Host { .. } => {
+                Some(socks5::failure(match rejection {
+                    Rejection::General | Rejection::Invalid | Rejection::Unauthenticated => {
+                        socks5::REP_GENERAL_FAILURE
+                    }
+                    Rejection::NotFound | Rejection::Unavailable | Rejection::HostUnreachable => {
+                        socks5::REP_HOST_UNREACHABLE
+                    }
+                    Rejection::NetworkUnreachable => socks5::REP_NETWORK_UNREACHABLE,
+                    Rejection::ConnectionRefused => socks5::REP_CONNECTION_REFUSED,
+                    Rejection::TimedOut => socks5::REP_TTL_EXPIRED,
+                    Rejection::Forbidden => socks5::REP_NOT_ALLOWED,
+                }))
+            }
+            // all we can do is hang up
+            ConnectType::Transparent { .. } => None,
+            ConnectType::InvalidHttpGet { .. } => unreachable!("not a connect"),
+        }
+    }
+}
+
+/// Give up on clients which haven't finished their handshake by this point.
+const MAX_HANDSHAKE: usize = 64 * 1024;
+
+async fn read_initialisation(
+    socket: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    auth: &Authenticator,
+    legacy_health: bool,
+) -> Result<Handshake> {
     loop {
-        let found = socket.read(&mut buf[progress..]).await?;
+        if buf.len() >= MAX_HANDSHAKE {
+            bail!("handshake longer than {} bytes", MAX_HANDSHAKE);
+        }
+        buf.reserve(4096);
+        let found = socket.read_buf(buf).await?;
         if 0 == found {
             bail!("unexpected eof reading header")
         }
-        progress += found;
-        let valid = &buf[..progress];
+        let valid = &buf[..];
         return match valid[0] {
-            // https-style CONNECT
-            b'C' | b'G' => {
+            // https-style CONNECT, or any plain http request
+            b'A'..=b'Z' => {
                 // curl -p -x http://localhost:3438 http://kube-dns.kube-system:9153/metrics
-                let mut headers = [httparse::EMPTY_HEADER; 16];
-                let mut req = httparse::Request::new(&mut headers);
-                if req.parse(&buf)?.is_partial() {
-                    continue;
-                }
+                // curl -x http://localhost:3438 http://kube-dns.kube-system:9153/metrics
+                let mut headers = vec![httparse::EMPTY_HEADER; 16];
+                let (req, status) = http::parse_request(&mut headers, valid)?;
+                let head_len = match status {
+                    httparse::Status::Complete(len) => len,
+                    httparse::Status::Partial => continue,
+                };
                 let path = req.path.ok_or(anyhow!("no path on a valid request?"))?;
-                let host_with_port = match req.method {
-                    Some("CONNECT") => path,
-                    Some("GET") => {
+                let connect = match req.method {
+                    Some("CONNECT") => match http::split_host_port(path) {
+                        Ok((hostname, port)) => ConnectType::Http {
+                            hostname: hostname.to_string(),
+                            port,
+                        },
+                        Err(err) => {
+                            socket
+                                .write_all(&http::error_response(Rejection::Invalid))
+                                .await?;
+                            return Err(err.context(format!("CONNECT to {:?}", path)));
+                        }
+                    },
+                    Some("GET") if legacy_health && path.starts_with('/') => {
                         return Ok(ConnectType::InvalidHttpGet {
                             path: path.to_string(),
-                        })
+                        }
+                        .into())
+                    }
+                    Some(_) if !path.starts_with('/') => ConnectType::HttpForward {
+                        head: valid.to_vec(),
+                    },
+                    method => {
+                        socket
+                            .write_all(&http::error_response(Rejection::Invalid))
+                            .await?;
+                        bail!("not a proxy request: {:?} {:?}", method, path)
                     }
-                    method => bail!("invalid method {:?}", method),
                 };
-                let colon = host_with_port
-                    .rfind(|c| c == ':')
-                    .ok_or(anyhow!("port required in hostname"))?;
-                let (hostname, port) = host_with_port.split_at(colon);
-                if port.is_empty() {
-                    bail!("empty port");
-                }
-                let port = u16::from_str(&port[1..])?;
-                Ok(ConnectType::Http {
-                    hostname: hostname.to_string(),
-                    port,
+
+                let identity = match http::credentials(req.headers) {
+                    Ok(Some(token)) => match auth.review(&token).await {
+                        Ok(Some(identity)) => Some(identity),
+                        Ok(None) => {
+                            socket
+                                .write_all(&http::error_response(Rejection::Unauthenticated))
+                                .await?;
+                            bail!("http client presented an invalid token");
+                        }
+                        Err(err) => {
+                            socket
+                                .write_all(&http::error_response(Rejection::Unavailable))
+                                .await?;
+                            return Err(err);
+                        }
+                    },
+                    Ok(None) => None,
+                    Err(err) => {
+                        socket
+                            .write_all(&http::error_response(Rejection::Unauthenticated))
+                            .await?;
+                        return Err(err);
+                    }
+                };
+
+                let (namespace, leftover) = match connect {
+                    // the forwarder reads the rest of the stream, and each request's namespace
+                    ConnectType::HttpForward { .. } => (None, Vec::new()),
+                    _ => (
+                        http::header(req.headers, "x-begonia-namespace")
+                            .and_then(requested_namespace),
+                        valid[head_len..].to_vec(),
+                    ),
+                };
+                Ok(Handshake {
+                    connect,
+                    identity,
+                    namespace,
+                    leftover,
                 })
             }
             // socks 4 + socks 4a
@@ -96,7 +374,8 @@ async fn read_initialisation(socket: &mut TcpStream, buf: &mut [u8]) -> Result<C
                     Some(pos) => fixed_header_len + pos,
                     None => continue,
                 };
-                // ignoring actual user data (typically empty anyway)
+                // typically empty, or the local user's name, but may choose the namespace
+                let namespace = namespace_in_username(&valid[fixed_header_len..user_end]);
 
                 // strip null
                 let user_end = user_end + 1;
@@ -111,14 +390,25 @@ async fn read_initialisation(socket: &mut TcpStream, buf: &mut [u8]) -> Result<C
                         Some(pos) => user_end + pos,
                         None => continue,
                     };
-                    Ok(ConnectType::Socks4Host {
-                        hostname: String::from_utf8(valid[user_end..hostname_end].to_vec())?,
-                        port,
+                    Ok(Handshake {
+                        namespace,
+                        leftover: valid[hostname_end + 1..].to_vec(),
+                        ..ConnectType::Socks4Host {
+                            hostname: String::from_utf8(valid[user_end..hostname_end].to_vec())?,
+                            port,
+                        }
+                        .into()
                     })
                 } else {
-                    Ok(ConnectType::Socks4Ip { ip, port })
+                    Ok(Handshake {
+                        namespace,
+                        leftover: valid[user_end..].to_vec(),
+                        ..ConnectType::Socks4Ip { ip, port }.into()
+                    })
                 }
             }
+            // socks 5
+            0x05 => socks5::handshake(socket, buf, auth).await,
             _ => {
                 bail!("unrecognised, {:?}", valid);
             }
@@ -131,81 +421,297 @@ fn socks4a_marker_ip(ip: &Ipv4Addr) -> bool {
     oc[0] == 0 && oc[1] == 0 && oc[2] == 0 && oc[3] != 0
 }
 
-async fn worker(resolve_ctx: ResolveCtx, mut source: TcpStream) -> Result<()> {
-    let peer = source.peer_addr()?;
+/// Everything a worker needs, cloned for each connection.
+#[derive(Clone)]
+struct WorkerCtx {
+    resolve: ResolveCtx,
+    auth: Authenticator,
+    policy: Enforcer,
+    egress: Egress,
+    connect: Strategy,
+    timeouts: Timeouts,
+    sessions: Registry,
+    /// answer `/healthcheck` on the proxy port too, for older probes
+    legacy_health: Option<Health>,
+}
+
+/// Why `establish` gave up, and what to tell the client.
+struct Refused {
+    rejection: Rejection,
+    cause: anyhow::Error,
+}
 
-    let mut buf = [0; 4096];
-    let init = read_initialisation(&mut source, &mut buf).await?;
-    let (hint, ok_message, addrs) = match init {
-        // TODO: these are all clearly the same
-        ConnectType::Http { hostname, port } => {
-            let addrs = resolve::resolve(resolve_ctx, &hostname, port).await?;
-            (
-                format!("HTTP CONNECT to {}", hostname),
-                b"HTTP/1.0 200 OK\r\n\r\n".to_vec(),
-                addrs,
+impl WorkerCtx {
+    async fn lookup(
+        &self,
+        resolve_ctx: ResolveCtx,
+        hostname: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>> {
+        self.timeouts
+            .limit(
+                Stage::Resolve,
+                resolve::resolve(resolve_ctx, hostname, port),
             )
+            .await
+    }
+
+    /// Check the resolved addresses against the policy, and connect to one of them.
+    async fn establish(
+        &self,
+        hint: &str,
+        resolved: Result<Vec<SocketAddr>>,
+        identity: Option<&Identity>,
+        record: &mut AccessRecord,
+    ) -> Result<Connected, Refused> {
+        let refused = |rejection, cause| Refused { rejection, cause };
+
+        let addrs = match resolved {
+            Ok(addrs) if !addrs.is_empty() => {
+                record.resolved = addrs.clone();
+                addrs
+            }
+            Ok(_) => {
+                return Err(refused(
+                    Rejection::Unavailable,
+                    anyhow!("{} resolved to no addresses", hint),
+                ))
+            }
+            Err(err) => {
+                let rejection = if Expired::is_in(&err) {
+                    Rejection::TimedOut
+                } else {
+                    Rejection::NotFound
+                };
+                return Err(refused(
+                    rejection,
+                    err.context(format!("resolving for {}", hint)),
+                ));
+            }
+        };
+
+        let addrs = match self.policy.permitted(identity, addrs).await {
+            Ok(addrs) if !addrs.is_empty() => addrs,
+            Ok(_) => {
+                return Err(refused(
+                    Rejection::Forbidden,
+                    anyhow!("{} denied by policy", hint),
+                ))
+            }
+            Err(err) => {
+                return Err(refused(
+                    Rejection::General,
+                    err.context(format!("checking policy for {}", hint)),
+                ))
+            }
+        };
+
+        match identity {
+            Some(identity) => info!(
+                "establishing {} for {:?} via {:?}",
+                hint, identity.username, addrs
+            ),
+            None => info!("establishing {} via {:?}", hint, addrs),
         }
-        ConnectType::Socks4Host { hostname, port } => {
-            let addrs = match resolve::resolve(resolve_ctx, &hostname, port).await {
-                Ok(addrs) => addrs,
-                Err(err) => {
-                    info!("invalid client request: {:?}", err);
-                    // 5b: generic rejection (no error propagation available)
-                    source.write_all(b"\0\x5b\0\0\0\0\0\0").await?;
-                    return Ok(());
-                }
-            };
-            // 5a: OK!, other fields irrelevant for a connect request
-            (
-                format!("Socks4a to {}", hostname),
-                b"\0\x5a\0\0\0\0\0\0".to_vec(),
-                addrs,
-            )
+        let connecting = async { Ok(self.egress.connect(&self.connect, &addrs).await?) };
+        let dest = match self.timeouts.limit(Stage::Connect, connecting).await {
+            Ok(dest) => dest,
+            Err(err) => {
+                return Err(refused(
+                    Rejection::from_error(&err),
+                    err.context(format!("connecting for {}", hint)),
+                ))
+            }
+        };
+        debug!("{} connected to {:?}", hint, dest.addr);
+        record.connected = Some(dest.addr);
+        Ok(dest)
+    }
+}
+
+async fn worker(ctx: WorkerCtx, mut source: TcpStream, record: &mut AccessRecord) -> Result<()> {
+    let mut buf = Vec::new();
+    let handshake = ctx
+        .timeouts
+        .limit(
+            Stage::Handshake,
+            read_initialisation(
+                &mut source,
+                &mut buf,
+                &ctx.auth,
+                ctx.legacy_health.is_some(),
+            ),
+        )
+        .await?;
+    proceed(ctx, source, handshake, record).await
+}
+
+/// For connections redirected to the transparent listener, where to go is already known.
+async fn transparent_worker(
+    ctx: WorkerCtx,
+    source: TcpStream,
+    interceptor: Interceptor,
+    record: &mut AccessRecord,
+) -> Result<()> {
+    let addr = interceptor.original_destination(&source)?;
+    proceed(
+        ctx,
+        source,
+        ConnectType::Transparent { addr }.into(),
+        record,
+    )
+    .await
+}
+
+async fn proceed(
+    mut ctx: WorkerCtx,
+    mut source: TcpStream,
+    handshake: Handshake,
+    record: &mut AccessRecord,
+) -> Result<()> {
+    let peer = source.peer_addr()?;
+    let Handshake {
+        connect: init,
+        identity,
+        namespace,
+        leftover,
+    } = handshake;
+
+    metrics::CONNECTIONS
+        .with_label_values(&[init.protocol()])
+        .inc();
+    record.protocol = Some(init.protocol());
+    record.quiet = matches!(init, ConnectType::InvalidHttpGet { .. });
+    if let Some((host, port)) = init.target() {
+        record.host = Some(host);
+        record.port = Some(port);
+    }
+    record.identity = identity.as_ref().map(|i| i.username.clone());
+
+    if let ConnectType::InvalidHttpGet { path } = &init {
+        let health = ctx
+            .legacy_health
+            .as_ref()
+            .expect("only accepted for legacy health");
+        source
+            .write_all(&admin::legacy_health(health, path))
+            .await?;
+        return Ok(());
+    }
+
+    if ctx.auth.required && identity.is_none() {
+        reject(&mut source, &init, Rejection::Unauthenticated).await?;
+        bail!("unauthenticated {:?} refused", init);
+    }
+
+    if let Some(namespace) = namespace {
+        if !resolve::is_namespace(&namespace) {
+            reject(&mut source, &init, Rejection::Invalid).await?;
+            bail!("client requested invalid namespace {:?}", namespace);
         }
-        ConnectType::Socks4Ip { ip, port } => {
-            let addr = SocketAddr::new(IpAddr::V4(ip), port);
-            // 5a: OK!, other fields irrelevant for a connect request
-            (
-                format!("Socks4 legacy to {:?}", ip),
-                b"\0\x5a\0\0\0\0\0\0".to_vec(),
-                vec![addr],
-            )
+        ctx.resolve.default_namespace = namespace;
+    }
+
+    let session = ctx.sessions.open(
+        peer,
+        init.protocol(),
+        init.target(),
+        record.identity.clone(),
+    );
+
+    let resolve_ctx = ctx.resolve.clone();
+    let (hint, resolved) = match &init {
+        ConnectType::Http { hostname, port } => (
+            format!("HTTP CONNECT to {}", hostname),
+            ctx.lookup(resolve_ctx, hostname, *port).await,
+        ),
+        ConnectType::Socks4Host { hostname, port } => (
+            format!("Socks4a to {}", hostname),
+            ctx.lookup(resolve_ctx, hostname, *port).await,
+        ),
+        ConnectType::Socks5Host { hostname, port } => (
+            format!("Socks5 to {}", hostname),
+            ctx.lookup(resolve_ctx, hostname, *port).await,
+        ),
+        ConnectType::Socks4Ip { ip, port } => (
+            format!("Socks4 legacy to {:?}", ip),
+            Ok(vec![SocketAddr::new(IpAddr::V4(*ip), *port)]),
+        ),
+        ConnectType::Socks5Ip { addr } => (format!("Socks5 to {:?}", addr), Ok(vec![*addr])),
+        ConnectType::Transparent { addr } => {
+            (format!("Transparent to {:?}", addr), Ok(vec![*addr]))
         }
 
-        ConnectType::InvalidHttpGet { path } => {
-            let msg = match path.as_ref() {
-                "/" => concat!("HTTP/1.0 200 OK\r\n\r\n", env!("CARGO_CRATE_NAME")),
-                "/healthcheck" => {
-                    "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{\"ok\":true}"
-                }
-                _ => "HTTP/1.0 404 NO\r\n\r\n",
-            };
-            source.write_all(msg.as_bytes()).await?;
-            return Ok(());
+        ConnectType::HttpForward { head } => {
+            let head = head.clone();
+            return http::forward(&ctx, source, head, identity.as_ref(), &session, record).await;
         }
+
+        ConnectType::InvalidHttpGet { .. } => unreachable!("answered above"),
     };
 
-    info!("establishing {} via {:?}", hint, addrs);
-    let dest = TcpStream::connect(&*addrs).await?;
-    source.write_all(&ok_message).await?;
+    let dest = match ctx
+        .establish(&hint, resolved, identity.as_ref(), record)
+        .await
+    {
+        Ok(dest) => dest,
+        Err(Refused { rejection, cause }) => {
+            reject(&mut source, &init, rejection).await?;
+            return Err(cause);
+        }
+    };
+    source.write_all(&init.ok_message(dest.bound)).await?;
+    let _active = metrics::ActiveTunnel::start();
 
-    let (mut source_read, mut source_write) = source.into_split();
-    let (mut dest_read, mut dest_write) = dest.into_split();
+    let (source_read, mut source_write) = source.into_split();
+    let (dest_read, mut dest_write) = tokio::io::split(dest.stream);
+    let activity = Activity::new();
+    let mut source_read = metrics::Counted::new(activity.watch(source_read), metrics::bytes_up())
+        .sharing(session.bytes_up());
+    let mut dest_read = metrics::Counted::new(activity.watch(dest_read), metrics::bytes_down())
+        .sharing(session.bytes_down());
 
     // let sent = tokio::io::copy(&mut source_read, &mut dest_write).await?;
     // dest_write.shutdown().await?;
 
-    tokio::try_join!(
-        copy_close(&mut source_read, &mut dest_write),
-        copy_close(&mut dest_read, &mut source_write),
-    )?;
+    // e.g. a tls client hello, sent without waiting for our reply
+    if !leftover.is_empty() {
+        debug!("{} forwarding {} early bytes", hint, leftover.len());
+        dest_write.write_all(&leftover).await?;
+        metrics::bytes_up().inc_by(leftover.len() as u64);
+        session
+            .bytes_up()
+            .fetch_add(leftover.len() as u64, Ordering::Relaxed);
+    }
+
+    let copied = tokio::select! {
+        copied = async {
+            tokio::try_join!(
+                copy_close(&mut source_read, &mut dest_write),
+                copy_close(&mut dest_read, &mut source_write),
+            )
+        } => copied.map(|_| ()).map_err(anyhow::Error::from),
+        expired = ctx.timeouts.expire(&activity) => Err(expired.into()),
+        _ = session.terminated() => {
+            Err(anyhow!("connection {} terminated by an operator", session.id()))
+        }
+    };
+    record.bytes_up = leftover.len() as u64 + source_read.count();
+    record.bytes_down = dest_read.count();
+    copied?;
 
     info!("{:?} exited cleanly", peer);
 
     Ok(())
 }
 
+async fn reject(source: &mut TcpStream, init: &ConnectType, rejection: Rejection) -> Result<()> {
+    if let Some(msg) = init.rejection_message(rejection) {
+        source.write_all(&msg).await?;
+    }
+    Ok(())
+}
+
 pub async fn copy_close<'a, R, W>(reader: &'a mut R, writer: &'a mut W) -> io::Result<u64>
 where
     R: AsyncRead + Unpin + ?Sized,
@@ -220,6 +726,8 @@ where
 pub async fn main() -> Result<()> {
     env_logger::init();
 
+    let config = Config::load()?;
+
     let client = Client::try_default().await.context("initialising client")?;
 
     let version_info = client
@@ -232,40 +740,163 @@ pub async fn main() -> Result<()> {
         version_info.major, version_info.minor
     );
 
-    let dns = find_dns(client.clone())
-        .await
-        .with_context(|| anyhow!("finding dns servers"))?;
-    info!("found kube-dns: {:?}", dns);
-
-    #[cfg(never)]
-    let pod = pods.get_status("svc-flags-78966c8947-45gds").await?;
-    #[cfg(never)]
-    println!(
-        "{:?}",
-        pod.status
-            .unwrap()
-            .pod_ips
-            .unwrap()
-            .into_iter()
-            .flat_map(|p| p.ip)
-            .collect::<Vec<_>>()
+    let default_namespace = config
+        .default_namespace
+        .clone()
+        .unwrap_or_else(|| client.default_namespace().to_string());
+    info!("default namespace: {:?}", default_namespace);
+
+    let own_addresses = interfaces::addresses().context("listing our own addresses")?;
+    info!("own addresses: {:?}", own_addresses);
+
+    let cache = if config.watch_cache {
+        Cache::start(&client, config.block_internal).await
+    } else {
+        Cache::disabled()
+    };
+
+    let (dns, egress) = match config.egress {
+        EgressMode::Direct => {
+            let dns_service = DnsService {
+                namespace: config.dns_namespace.clone(),
+                name: config.dns_service.clone(),
+                port: config.dns_port,
+            };
+            let dns = KubeDns::start(client.clone(), dns_service, config.dns_cache()).await;
+            (Some(dns), Egress::Direct)
+        }
+        EgressMode::Apiserver => {
+            info!("tunnelling through the api server; service names resolve via endpoints");
+            let egress = Egress::ApiServer {
+                client: client.clone(),
+                cache: cache.clone(),
+            };
+            (None, egress)
+        }
+    };
+
+    info!("client authentication required: {}", config.require_auth);
+    info!(
+        "policy: {} rules, default {:?}",
+        config.policy.rules.len(),
+        config.policy.default
     );
+    if !config.block_internal {
+        warn!("loopback, link-local, metadata, node and our own addresses are reachable");
+    }
+    let shutdown = Shutdown::default();
+    let resolve = ResolveCtx {
+        cluster_local: config.cluster_domain.clone(),
+        client: client.clone(),
+        cache: cache.clone(),
+        default_namespace,
+        dns,
+    };
+    let sessions = Registry::default();
+    let health = Health::new(resolve.clone(), shutdown.clone());
+    let auth = Authenticator::new(client.clone(), config.require_auth);
+    let admin = Admin::new(
+        health.clone(),
+        sessions.clone(),
+        auth.clone(),
+        config.admin_users.clone(),
+    );
+    let policy = Enforcer::new(
+        config.policy.clone(),
+        config.block_internal,
+        own_addresses.clone(),
+        client.clone(),
+        cache,
+    );
+    if config.block_internal {
+        match policy.can_list_nodes().await {
+            Ok(true) => (),
+            Ok(false) => error!(
+                "forbidden from listing nodes, so every connection will be refused; \
+                 grant list and watch on nodes, or turn off block_internal"
+            ),
+            Err(err) => warn!("{:?} checking node access", err),
+        }
+    }
+    let ctx = WorkerCtx {
+        resolve,
+        auth,
+        policy,
+        egress,
+        connect: config.connect_strategy(),
+        timeouts: config.timeouts(),
+        sessions,
+        legacy_health: if config.legacy_health {
+            Some(health.clone())
+        } else {
+            None
+        },
+    };
+
+    info!("serving health and metrics on {:?}", config.admin_listen);
+    let admin_listener = TcpListener::bind(config.admin_listen).await?;
+    tokio::spawn(async move {
+        if let Err(e) = admin.serve(admin_listener).await {
+            error!("{:?} serving admin requests", e);
+        }
+    });
 
-    let addr = "[::]:3438";
+    let addr = config.listen;
     info!("binding to {:?}", addr);
     let listener = TcpListener::bind(addr).await?;
+    let transparent = match config.transparent_listen {
+        Some(addr) => {
+            info!(
+                "accepting {:?} redirected connections on {:?}",
+                config.transparent_mode, addr
+            );
+            let listener = transparent::bind(addr, config.transparent_mode)?;
+            let listening = vec![config.listen, config.admin_listen, listener.local_addr()?];
+            let interceptor =
+                Interceptor::new(config.transparent_mode, listening, own_addresses.clone());
+            Some((listener, interceptor))
+        }
+        None => None,
+    };
+    let signalled = shutdown::signalled();
+    tokio::pin!(signalled);
     loop {
-        let (socket, client_addr) = listener.accept().await?;
-        let resolve_ctx = ResolveCtx {
-            cluster_local: "cluster.local".to_string(),
-            client: client.clone(),
-            default_namespace: "default".to_string(),
-            dns_servers: dns.clone(),
+        let (socket, client_addr, interceptor) = tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, client_addr) = accepted?;
+                (socket, client_addr, None)
+            }
+            accepted = transparent::accept(transparent.as_ref().map(|(listener, _)| listener)) => {
+                let (socket, client_addr) = accepted?;
+                (socket, client_addr, transparent.as_ref().map(|(_, i)| i.clone()))
+            }
+            signal = &mut signalled => {
+                info!("received {}, no longer accepting connections", signal);
+                break;
+            }
         };
+        let ctx = ctx.clone();
+        let tracked = shutdown.track();
         tokio::spawn(async move {
-            if let Err(e) = worker(resolve_ctx, socket).await {
+            let _tracked = tracked;
+            let mut record = AccessRecord::new(client_addr);
+            let result = match interceptor {
+                Some(interceptor) => {
+                    transparent_worker(ctx, socket, interceptor, &mut record).await
+                }
+                None => worker(ctx, socket, &mut record).await,
+            };
+            if let Err(e) = &result {
                 error!("{:?} handling {:?}", e, client_addr);
             }
+            record.finish(result.err().as_ref());
         });
     }
+
+    drop(listener);
+    drop(transparent);
+    shutdown
+        .drain(Duration::from_secs(config.drain_timeout_secs))
+        .await;
+    Ok(())
 }
@@ -0,0 +1,141 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+lazy_static! {
+    pub static ref CONNECTIONS: IntCounterVec = register_int_counter_vec!(
+        "begonia_connections_total",
+        "Connections which completed a handshake, by protocol",
+        &["protocol"]
+    )
+    .unwrap();
+    static ref RESOLVE_SECONDS: HistogramVec = register_histogram_vec!(
+        "begonia_resolve_duration_seconds",
+        "Time taken to resolve a target, by scheme",
+        &["scheme"]
+    )
+    .unwrap();
+    static ref RESOLVE_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "begonia_resolve_failures_total",
+        "Targets which failed to resolve, by scheme",
+        &["scheme"]
+    )
+    .unwrap();
+    pub static ref ACTIVE_TUNNELS: IntGauge =
+        register_int_gauge!("begonia_active_tunnels", "Tunnels currently open").unwrap();
+    static ref TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "begonia_timeouts_total",
+        "Connections which ran out of time, by the limit reached",
+        &["stage"]
+    )
+    .unwrap();
+    static ref BYTES: IntCounterVec = register_int_counter_vec!(
+        "begonia_tunnel_bytes_total",
+        "Bytes copied through tunnels; up is client to upstream",
+        &["direction"]
+    )
+    .unwrap();
+}
+
+pub fn observe_resolution(scheme: &str, took: Duration, ok: bool) {
+    RESOLVE_SECONDS
+        .with_label_values(&[scheme])
+        .observe(took.as_secs_f64());
+    if !ok {
+        RESOLVE_FAILURES.with_label_values(&[scheme]).inc();
+    }
+}
+
+pub fn observe_timeout(stage: &str) {
+    TIMEOUTS.with_label_values(&[stage]).inc();
+}
+
+pub fn bytes_up() -> IntCounter {
+    BYTES.with_label_values(&["up"])
+}
+
+pub fn bytes_down() -> IntCounter {
+    BYTES.with_label_values(&["down"])
+}
+
+/// The prometheus text exposition format.
+pub fn render() -> String {
+    let mut buf = Vec::with_capacity(4096);
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("encoding to a vec");
+    String::from_utf8(buf).expect("text format is utf-8")
+}
+
+/// Decrements the active tunnel count when the tunnel ends, however it ends.
+pub struct ActiveTunnel(());
+
+impl ActiveTunnel {
+    pub fn start() -> ActiveTunnel {
+        ACTIVE_TUNNELS.inc();
+        ActiveTunnel(())
+    }
+}
+
+impl Drop for ActiveTunnel {
+    fn drop(&mut self) {
+        ACTIVE_TUNNELS.dec();
+    }
+}
+
+/// Adds everything read through it to a counter, as it happens, and keeps its own total.
+pub struct Counted<R> {
+    inner: R,
+    counter: IntCounter,
+    count: u64,
+    shared: Option<Arc<AtomicU64>>,
+}
+
+impl<R> Counted<R> {
+    pub fn new(inner: R, counter: IntCounter) -> Counted<R> {
+        Counted {
+            inner,
+            counter,
+            count: 0,
+            shared: None,
+        }
+    }
+
+    /// Also add to a total someone else is watching.
+    pub fn sharing(mut self, total: Arc<AtomicU64>) -> Counted<R> {
+        self.shared = Some(total);
+        self
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.counter.inc_by(read as u64);
+        self.count += read as u64;
+        if let Some(shared) = &self.shared {
+            shared.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
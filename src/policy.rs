@@ -0,0 +1,491 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use ipnet::IpNet;
+use k8s_openapi::api::core::v1::{Node, Service};
+use kube::api::ListParams;
+use kube::{Api, Client};
+use log::{debug, info};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::auth::Identity;
+use crate::cache::Cache;
+
+/// Cloud metadata services which aren't already covered by being link-local.
+const METADATA: [IpAddr; 2] = [
+    // aws, over ipv6
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0x0ec2, 0, 0, 0, 0, 0, 0x0254)),
+    // alibaba
+    IpAddr::V4(Ipv4Addr::new(100, 100, 100, 200)),
+];
+
+/// Without the watch cache, how long a list of the nodes' addresses is used for.
+const NODE_REFRESH: Duration = Duration::from_secs(60);
+
+type NodeIps = Arc<HashSet<String>>;
+
+/// Addresses which are never reasonable to tunnel to: the proxy itself, the node's
+/// link-local services (including most clouds' metadata at `169.254.169.254`), and the like.
+fn is_internal(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let local = match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unspecified(),
+    };
+    local || METADATA.contains(&ip)
+}
+
+/// Which destinations clients may reach. Rules are checked in order, and the first
+/// which matches decides; if none do, `default` does.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub default: Action,
+    #[serde(rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Every condition which is given must hold; empty lists match anything.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Action,
+    /// authenticated usernames, e.g. `system:serviceaccount:ci:runner`
+    #[serde(default)]
+    pub clients: Vec<String>,
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    /// names of services or pods
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// like a `matchLabels` selector, against the pod's labels, or a service's selector
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub cidrs: Vec<IpNet>,
+    /// the port actually connected to; for names resolved through endpoints, that's the
+    /// pod's port, e.g. 8080, not the service port the client asked for
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+/// Something in the cluster which owns an address.
+#[derive(Debug)]
+struct Owner {
+    namespace: String,
+    /// the pod, and any services selecting it; or just the service, for a cluster ip
+    names: Vec<String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl Rule {
+    /// whether we need to look up who owns the address to evaluate the rule
+    fn needs_owners(&self) -> bool {
+        !self.namespaces.is_empty() || !self.names.is_empty() || !self.labels.is_empty()
+    }
+
+    fn matches(&self, identity: Option<&Identity>, addr: SocketAddr, owners: &[Owner]) -> bool {
+        if !self.clients.is_empty()
+            && !identity.is_some_and(|identity| self.clients.contains(&identity.username))
+        {
+            return false;
+        }
+        if !self.ports.is_empty() && !self.ports.contains(&addr.port()) {
+            return false;
+        }
+        if !self.cidrs.is_empty() && !self.cidrs.iter().any(|net| net.contains(&addr.ip())) {
+            return false;
+        }
+        if !self.needs_owners() {
+            return true;
+        }
+        owners.iter().any(|owner| {
+            (self.namespaces.is_empty() || self.namespaces.contains(&owner.namespace))
+                && (self.names.is_empty() || owner.names.iter().any(|n| self.names.contains(n)))
+                && self
+                    .labels
+                    .iter()
+                    .all(|(k, v)| owner.labels.get(k) == Some(v))
+        })
+    }
+}
+
+/// Applies the policy to resolved addresses, looking up what they belong to when needed.
+#[derive(Clone)]
+pub struct Enforcer {
+    policy: Arc<Policy>,
+    /// refuse loopback, link-local, metadata, node and our own addresses, whatever the policy says
+    block_internal: bool,
+    /// ours, so clients can't reach the admin listener, or loop back through the proxy
+    own: Arc<Vec<IpAddr>>,
+    client: Client,
+    cache: Cache,
+    /// when the cache can't answer, and when we last listed them
+    node_ips: Arc<Mutex<Option<(Instant, NodeIps)>>>,
+}
+
+impl Enforcer {
+    pub fn new(
+        policy: Policy,
+        block_internal: bool,
+        own: Vec<IpAddr>,
+        client: Client,
+        cache: Cache,
+    ) -> Enforcer {
+        Enforcer {
+            policy: Arc::new(policy),
+            block_internal,
+            own: Arc::new(own.into_iter().map(|ip| ip.to_canonical()).collect()),
+            client,
+            cache,
+            node_ips: Arc::default(),
+        }
+    }
+
+    /// Whether we may list nodes, without which every connection is refused, as any address
+    /// could be a node's; `Ok(false)` if the apiserver forbids it.
+    pub async fn can_list_nodes(&self) -> Result<bool> {
+        match Api::<Node>::all(self.client.clone())
+            .list(&ListParams::default().limit(1))
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(response)) if response.code == 403 => Ok(false),
+            Err(err) => Err(err).with_context(|| anyhow!("listing nodes")),
+        }
+    }
+
+    /// The addresses this client may connect to.
+    pub async fn permitted(
+        &self,
+        identity: Option<&Identity>,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>> {
+        let mut permitted = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            if self.block_internal && self.is_blocked(addr.ip()).await? {
+                info!("refusing internal address {}", addr);
+                continue;
+            }
+            if self.policy.rules.is_empty() && self.policy.default == Action::Allow {
+                permitted.push(addr);
+                continue;
+            }
+            match self.decide(identity, addr).await? {
+                Action::Allow => permitted.push(addr),
+                Action::Deny => info!(
+                    "policy denies {:?} access to {}",
+                    identity.map(|i| &i.username),
+                    addr
+                ),
+            }
+        }
+        Ok(permitted)
+    }
+
+    async fn is_blocked(&self, ip: IpAddr) -> Result<bool> {
+        if is_internal(ip) || self.own.contains(&ip.to_canonical()) {
+            return Ok(true);
+        }
+        let ip = ip.to_canonical().to_string();
+        let is_node = |node: &Node| {
+            node.status
+                .as_ref()
+                .and_then(|status| status.addresses.as_ref())
+                .is_some_and(|addresses| {
+                    addresses
+                        .iter()
+                        .any(|a| a.type_ == "InternalIP" && a.address == ip)
+                })
+        };
+        if let Some(nodes) = self.cache.nodes.filter(is_node) {
+            return Ok(!nodes.is_empty());
+        }
+        Ok(self.node_ips().await?.contains(&ip))
+    }
+
+    /// held while listing, so a burst of connections only lists the nodes once
+    async fn node_ips(&self) -> Result<NodeIps> {
+        let mut cached = self.node_ips.lock().await;
+        if let Some((at, ips)) = cached.as_ref() {
+            if at.elapsed() < NODE_REFRESH {
+                return Ok(ips.clone());
+            }
+        }
+        let ips: HashSet<String> = Api::<Node>::all(self.client.clone())
+            .list(&ListParams::default())
+            .await
+            .with_context(|| anyhow!("listing nodes"))?
+            .items
+            .into_iter()
+            .filter_map(|node| node.status?.addresses)
+            .flatten()
+            .filter(|a| a.type_ == "InternalIP")
+            .map(|a| a.address)
+            .collect();
+        let ips = Arc::new(ips);
+        *cached = Some((Instant::now(), ips.clone()));
+        Ok(ips)
+    }
+
+    async fn decide(&self, identity: Option<&Identity>, addr: SocketAddr) -> Result<Action> {
+        let mut owners = None;
+        for rule in &self.policy.rules {
+            if rule.needs_owners() && owners.is_none() {
+                owners = Some(self.owners(addr.ip()).await?);
+            }
+            if rule.matches(identity, addr, owners.as_deref().unwrap_or_default()) {
+                debug!("{} matched {:?}", addr, rule);
+                return Ok(rule.action);
+            }
+        }
+        Ok(self.policy.default)
+    }
+
+    async fn owners(&self, ip: IpAddr) -> Result<Vec<Owner>> {
+        let mut owners = Vec::new();
+
+        for pod in self.cache.pods_with_ip(&self.client, None, ip).await? {
+            let labels = pod.metadata.labels.clone().unwrap_or_default();
+            let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+            let mut names: Vec<String> = pod.metadata.name.iter().cloned().collect();
+            for service in self.services(Some(&namespace)).await? {
+                let selects = service
+                    .spec
+                    .as_ref()
+                    .and_then(|spec| spec.selector.as_ref())
+                    .is_some_and(|selector| {
+                        !selector.is_empty()
+                            && selector.iter().all(|(k, v)| labels.get(k) == Some(v))
+                    });
+                if selects {
+                    names.extend(service.metadata.name.clone());
+                }
+            }
+            owners.push(Owner {
+                namespace,
+                names,
+                labels: labels.into_iter().collect(),
+            });
+        }
+
+        let ip = ip.to_string();
+        for service in self.services(None).await? {
+            let spec = match &service.spec {
+                Some(spec) => spec,
+                None => continue,
+            };
+            let has_ip = spec.cluster_ip.as_ref() == Some(&ip)
+                || spec.cluster_ips.iter().flatten().any(|c| c == &ip);
+            if !has_ip {
+                continue;
+            }
+            owners.push(Owner {
+                namespace: service.metadata.namespace.clone().unwrap_or_default(),
+                names: service.metadata.name.iter().cloned().collect(),
+                labels: spec
+                    .selector
+                    .clone()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+            });
+        }
+
+        Ok(owners)
+    }
+
+    /// in the namespace, or everywhere; without the cache, this is a full list each time
+    async fn services(&self, ns: Option<&str>) -> Result<Vec<Arc<Service>>> {
+        let cached = self.cache.services.filter(|service| {
+            ns.is_none_or(|ns| service.metadata.namespace.as_deref() == Some(ns))
+        });
+        if let Some(services) = cached {
+            return Ok(services);
+        }
+        let api = match ns {
+            Some(ns) => Api::<Service>::namespaced(self.client.clone(), ns),
+            None => Api::<Service>::all(self.client.clone()),
+        };
+        Ok(api
+            .list(&ListParams::default())
+            .await
+            .with_context(|| anyhow!("listing services"))?
+            .items
+            .into_iter()
+            .map(Arc::new)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn policy(text: &str) -> Policy {
+        toml::from_str(text).expect("valid policy")
+    }
+
+    fn rule(text: &str) -> Rule {
+        policy(&format!("[[rule]]\naction = \"allow\"\n{}", text))
+            .rules
+            .remove(0)
+    }
+
+    fn identity(username: &str) -> Identity {
+        Identity {
+            username: username.to_string(),
+        }
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn owner(namespace: &str, names: &[&str], labels: &[(&str, &str)]) -> Owner {
+        Owner {
+            namespace: namespace.to_string(),
+            names: names.iter().map(|n| n.to_string()).collect(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    fn enforcer(policy: Policy) -> Enforcer {
+        let client = Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap()))
+            .expect("client");
+        Enforcer::new(policy, false, Vec::new(), client, Cache::disabled())
+    }
+
+    #[test]
+    fn empty_rules_match_anything() {
+        let rule = rule("");
+        assert!(!rule.needs_owners());
+        assert!(rule.matches(None, addr("10.0.0.1:80"), &[]));
+    }
+
+    #[test]
+    fn clients_need_a_matching_identity() {
+        let rule = rule("clients = [\"system:serviceaccount:ci:runner\"]");
+        let runner = identity("system:serviceaccount:ci:runner");
+        let other = identity("system:serviceaccount:ci:other");
+        assert!(rule.matches(Some(&runner), addr("10.0.0.1:80"), &[]));
+        assert!(!rule.matches(Some(&other), addr("10.0.0.1:80"), &[]));
+        assert!(!rule.matches(None, addr("10.0.0.1:80"), &[]));
+    }
+
+    #[test]
+    fn cidrs_and_ports_are_checked_against_the_address() {
+        let rule = rule("cidrs = [\"10.0.0.0/8\", \"fd00::/8\"]\nports = [80, 443]");
+        assert!(rule.matches(None, addr("10.1.2.3:80"), &[]));
+        assert!(rule.matches(None, addr("[fd00::1]:443"), &[]));
+        assert!(!rule.matches(None, addr("10.1.2.3:8080"), &[]));
+        assert!(!rule.matches(None, addr("192.168.0.1:80"), &[]));
+    }
+
+    #[test]
+    fn namespaces_names_and_labels_are_checked_against_the_owners() {
+        let web = || {
+            owner(
+                "shop",
+                &["web-0", "web"],
+                &[("app", "web"), ("tier", "front")],
+            )
+        };
+        let db = || owner("data", &["db"], &[("app", "db")]);
+
+        let rule_ns = rule("namespaces = [\"shop\"]");
+        assert!(rule_ns.needs_owners());
+        assert!(rule_ns.matches(None, addr("10.0.0.1:80"), &[db(), web()]));
+        assert!(!rule_ns.matches(None, addr("10.0.0.1:80"), &[db()]));
+        assert!(!rule_ns.matches(None, addr("10.0.0.1:80"), &[]));
+
+        let rule_names = rule("names = [\"web\"]");
+        assert!(rule_names.matches(None, addr("10.0.0.1:80"), &[web()]));
+        assert!(!rule_names.matches(None, addr("10.0.0.1:80"), &[db()]));
+
+        let rule_labels = rule("labels = { app = \"web\", tier = \"front\" }");
+        assert!(rule_labels.matches(None, addr("10.0.0.1:80"), &[web()]));
+        let partial = owner("shop", &["web"], &[("app", "web")]);
+        assert!(!rule_labels.matches(None, addr("10.0.0.1:80"), &[partial]));
+
+        // every condition must hold for the same owner
+        let both = rule("namespaces = [\"data\"]\nnames = [\"web\"]");
+        assert!(!both.matches(None, addr("10.0.0.1:80"), &[web(), db()]));
+    }
+
+    #[tokio::test]
+    async fn the_first_matching_rule_decides() {
+        let enforcer = enforcer(policy(
+            r#"
+            default = "deny"
+            [[rule]]
+            action = "deny"
+            ports = [22]
+            [[rule]]
+            action = "allow"
+            cidrs = ["10.0.0.0/8"]
+            "#,
+        ));
+        let decide = |a| enforcer.decide(None, addr(a));
+        assert_eq!(Action::Deny, decide("10.0.0.1:22").await.unwrap());
+        assert_eq!(Action::Allow, decide("10.0.0.1:80").await.unwrap());
+        assert_eq!(Action::Deny, decide("192.168.0.1:80").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn the_default_applies_when_nothing_matches() {
+        let enforcer = enforcer(policy("[[rule]]\naction = \"deny\"\nports = [22]"));
+        let permitted = enforcer
+            .permitted(None, vec![addr("10.0.0.1:22"), addr("10.0.0.1:80")])
+            .await
+            .unwrap();
+        assert_eq!(vec![addr("10.0.0.1:80")], permitted);
+    }
+
+    #[test]
+    fn internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "127.1.2.3",
+            "::1",
+            "::ffff:127.0.0.1",
+            "0.0.0.0",
+            "::",
+            "169.254.169.254",
+            "::ffff:169.254.169.254",
+            "fe80::1",
+            "fd00:ec2::254",
+            "100.100.100.200",
+        ] {
+            assert!(is_internal(ip.parse().unwrap()), "{} is internal", ip);
+        }
+        for ip in [
+            "10.0.0.1",
+            "192.168.1.1",
+            "8.8.8.8",
+            "fd00::1",
+            "100.100.100.201",
+        ] {
+            assert!(!is_internal(ip.parse().unwrap()), "{} isn't internal", ip);
+        }
+    }
+}
@@ -1,33 +1,52 @@
-use std::collections::HashSet;
-use std::convert::TryFrom;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
-use hickory_resolver::Name;
-use k8s_openapi::api::core::v1::Endpoints;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use k8s_openapi::api::core::v1::{Pod, Service};
 use kube::Api;
 use lazy_static::lazy_static;
 use regex::Regex;
 
+use crate::cache::Cache;
+use crate::dns::KubeDns;
+use crate::endpoints;
+use crate::endpoints::GroupPort;
+use crate::metrics;
+
+lazy_static! {
+    static ref CUSTOM_SCHEME: Regex = Regex::new(concat!(
+        "^((?:[a-zA-Z0-9-]{1,63}\\.){0,2}[a-zA-Z0-9-]{1,63})",
+        "\\.(endpoints|pod|pod-by-name)\\.local\\.?$"
+    ))
+    .unwrap();
+}
+
 #[derive(Clone)]
 pub struct ResolveCtx {
     pub cluster_local: String,
     pub default_namespace: String,
     pub client: kube::Client,
-    pub dns_servers: Vec<IpAddr>,
+    pub cache: Cache,
+    /// `None` outside the cluster, where kube-dns isn't reachable
+    pub dns: Option<KubeDns>,
 }
 
 // resolution order:
 // $0.$default.endpoints.local.
 // $0.endpoints.local.
-// # $0.$default.pod.local.
-// # $0.pod.local.
-// # $0.$default.pod-by-name.local.
-// # $0.pod-by-name.local.
+// $port.$0.$default.endpoints.local.
+// $0.$default.pod.local.
+// $0.pod.local.
+// $0.$default.pod-by-name.local.
+// $0.pod-by-name.local.
 
 // then: cluster dns with:
 // $0.$default.svc.cluster.local
@@ -38,99 +57,381 @@ pub(crate) async fn resolve(
     hostname: &str,
     specified_port: u16,
 ) -> Result<Vec<SocketAddr>> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(concat!(
-            "^([a-zA-Z0-9-]{1,63})(?:\\.([a-zA-Z0-9-]{1,63}))?",
-            "\\.(endpoints|pod|pod-by-name)\\.local\\.?$"
-        ))
-        .unwrap();
-    }
+    let scheme = match CUSTOM_SCHEME.captures(hostname) {
+        Some(custom) => match &custom[2] {
+            "endpoints" => "endpoints",
+            "pod" => "pod",
+            _ => "pod-by-name",
+        },
+        None if ctx.dns.is_none() => "service",
+        None => "kube-dns",
+    };
+
+    let start = Instant::now();
+    let result = resolve_uninstrumented(ctx, hostname, specified_port).await;
+    metrics::observe_resolution(scheme, start.elapsed(), result.is_ok());
+    result
+}
+
+async fn resolve_uninstrumented(
+    ctx: ResolveCtx,
+    hostname: &str,
+    specified_port: u16,
+) -> Result<Vec<SocketAddr>> {
+    if let Some(custom) = CUSTOM_SCHEME.captures(hostname) {
+        let labels: Vec<&str> = custom[1].split('.').collect();
+        let (port_name, name, ns) = match labels.as_slice() {
+            [name] => (None, *name, None),
+            [name, ns] => (None, *name, Some(*ns)),
+            [port_name, name, ns] => (Some(*port_name), *name, Some(*ns)),
+            _ => unreachable!("regex accepts one to three labels"),
+        };
+        let ns: String = ns
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| ctx.default_namespace.clone());
+        let command: &str = &custom[2];
 
-    if let Some(custom) = RE.captures(&hostname) {
-        let name: &str = &custom[1];
-        let ns: String = custom
-            .get(2)
-            .map(|v| v.as_str().to_string())
-            .unwrap_or(ctx.default_namespace);
-        let command: &str = &custom[3];
+        if port_name.is_some() && command != "endpoints" {
+            bail!(
+                "port names are only supported for endpoints: {:?}",
+                hostname
+            );
+        }
 
         match command {
             "endpoints" => {
-                let mut ips = Vec::with_capacity(16);
-                let mut ports = HashSet::<i32>::with_capacity(4);
-                let endpoints = Api::<Endpoints>::namespaced(ctx.client.clone(), &ns)
-                    .get(name)
-                    .await?;
-
-                // lazily ignoring the idea that there might be differences between sets
-                for subset in endpoints.subsets.unwrap_or_default() {
-                    for port in subset.ports.unwrap_or_default() {
-                        ports.insert(port.port);
-                    }
-
-                    for address in subset.addresses.unwrap_or_default() {
-                        ips.push(
-                            IpAddr::from_str(&address.ip)
-                                .with_context(|| anyhow!("parsing {:?}", address.ip))?,
-                        );
-                    }
-                }
+                return resolve_endpoints(&ctx, &ns, name, port_name, specified_port).await;
+            }
+            "pod" => {
+                let ip = parse_dashed_ip(name)?;
 
-                if ports.is_empty() || ips.is_empty() {
-                    return Ok(Vec::new());
+                // like kube-dns' "pods verified" mode: the pod must actually exist in the namespace
+                let found = ctx.cache.pods_with_ip(&ctx.client, Some(&ns), ip).await?;
+                if found.is_empty() {
+                    bail!("no pod with ip {} in {:?}", ip, ns);
                 }
 
-                let port = if ports.len() == 1 {
-                    u16::try_from(
-                        ports
-                            .into_iter()
-                            .next()
-                            .expect("is of length one so has an element"),
-                    )?
-                } else {
-                    // TODO: map using actual service
-                    specified_port
+                return Ok(vec![SocketAddr::new(ip, specified_port)]);
+            }
+            "pod-by-name" => {
+                let pod = match ctx.cache.pods.get(&ns, name) {
+                    Some(Some(pod)) => pod,
+                    Some(None) => bail!("no pod {}/{}", ns, name),
+                    None => Arc::new(
+                        Api::<Pod>::namespaced(ctx.client.clone(), &ns)
+                            .get(name)
+                            .await?,
+                    ),
                 };
-
-                return Ok(ips
+                return Ok(pod_ips(&pod)?
                     .into_iter()
-                    .map(|ip| SocketAddr::new(ip, port))
+                    .map(|ip| SocketAddr::new(ip, specified_port))
                     .collect());
             }
-            _ => unimplemented!("{:?}", command),
+            _ => bail!("unsupported command {:?}", command),
         }
     }
 
-    Ok(resolve_against_kube_dns(ctx, &hostname)
+    let dns = match &ctx.dns {
+        Some(dns) => dns.clone(),
+        None => return resolve_without_dns(&ctx, hostname, specified_port).await,
+    };
+
+    Ok(resolve_against_kube_dns(&ctx, &dns, hostname)
         .await?
         .into_iter()
         .map(|ip| SocketAddr::new(ip, specified_port))
         .collect())
 }
 
-async fn resolve_against_kube_dns(ctx: ResolveCtx, hostname: &str) -> Result<Vec<IpAddr>> {
+/// Service names, the way kube-dns would have them, but looked up via the endpoints:
+// $0
+// $0.$ns
+// $0.$ns.svc
+// $0.$ns.svc.cluster.local
+async fn resolve_without_dns(
+    ctx: &ResolveCtx,
+    hostname: &str,
+    specified_port: u16,
+) -> Result<Vec<SocketAddr>> {
+    if let Ok(ip) = IpAddr::from_str(hostname) {
+        return Ok(vec![SocketAddr::new(ip, specified_port)]);
+    }
+
+    let trimmed = hostname.trim_end_matches('.');
+    let trimmed = trimmed
+        .strip_suffix(&format!(".{}", ctx.cluster_local))
+        .unwrap_or(trimmed);
+    let labels: Vec<&str> = trimmed.split('.').collect();
+    let (name, ns) = match labels.as_slice() {
+        [name] => (*name, ctx.default_namespace.as_str()),
+        [name, ns] | [name, ns, "svc"] => (*name, *ns),
+        _ => bail!("{:?} isn't a service name, and there's no dns", hostname),
+    };
+
+    resolve_endpoints(ctx, ns, name, None, specified_port).await
+}
+
+/// Is this a valid namespace name, i.e. an rfc 1123 label.
+pub fn is_namespace(name: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new("^[a-z0-9](?:[a-z0-9-]{0,61}[a-z0-9])?$").unwrap();
+    }
+    RE.is_match(name)
+}
+
+/// Which of an endpoint's ports the client meant.
+#[derive(Debug)]
+enum WantedPort {
+    /// an endpoint port name; a service port's name is also its endpoint port's name
+    Named(Option<String>),
+    /// no service to map through: whatever the client said, or the only port there is
+    Client(u16),
+}
+
+impl WantedPort {
+    fn select(&self, ports: &[GroupPort]) -> Option<u16> {
+        let found = match self {
+            WantedPort::Named(name) => ports.iter().find(|p| &p.name == name),
+            WantedPort::Client(port) => match ports {
+                [only] => Some(only),
+                _ => ports.iter().find(|p| *port == p.port),
+            },
+        };
+        found.map(|p| p.port)
+    }
+}
+
+async fn resolve_endpoints(
+    ctx: &ResolveCtx,
+    ns: &str,
+    name: &str,
+    port_name: Option<&str>,
+    specified_port: u16,
+) -> Result<Vec<SocketAddr>> {
+    let groups = match ctx.cache.endpoint_groups(ns, name) {
+        Some(groups) => groups?,
+        None => endpoints::fetch(&ctx.client, ns, name).await?,
+    };
+
+    let service = match ctx.cache.services.get(ns, name) {
+        Some(service) => service,
+        None => Api::<Service>::namespaced(ctx.client.clone(), ns)
+            .get_opt(name)
+            .await?
+            .map(Arc::new),
+    };
+
+    let wanted = match port_name {
+        Some(port_name) => WantedPort::Named(Some(port_name.to_string())),
+        None => match service
+            .as_ref()
+            .and_then(|service| service.spec.as_ref())
+            .and_then(|spec| spec.ports.as_ref())
+            .and_then(|ports| ports.iter().find(|p| p.port == i32::from(specified_port)))
+        {
+            Some(service_port) => {
+                WantedPort::Named(service_port.name.clone().filter(|name| !name.is_empty()))
+            }
+            None => WantedPort::Client(specified_port),
+        },
+    };
+
+    let mut addrs = Vec::with_capacity(16);
+    let mut any_addresses = false;
+
+    // each group has its own ports, e.g. a named targetPort which differs between pods
+    for group in groups {
+        any_addresses |= !group.addresses.is_empty();
+        let port = match wanted.select(&group.ports) {
+            Some(port) => port,
+            None => continue,
+        };
+
+        addrs.extend(
+            group
+                .addresses
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port)),
+        );
+    }
+
+    if any_addresses && addrs.is_empty() {
+        bail!("no port matching {:?} on endpoints {}/{}", wanted, ns, name);
+    }
+
+    Ok(addrs)
+}
+
+/// `10-1-2-3` or `fd00--1-2` (the kube-dns `pod.` form) to an address
+fn parse_dashed_ip(name: &str) -> Result<IpAddr> {
+    let dotted = name.replace('-', ".");
+    if let Ok(ip) = IpAddr::from_str(&dotted) {
+        return Ok(ip);
+    }
+    IpAddr::from_str(&name.replace('-', ":"))
+        .with_context(|| anyhow!("{:?} is not a dashed pod ip", name))
+}
+
+fn pod_ips(pod: &Pod) -> Result<Vec<IpAddr>> {
+    let status = match &pod.status {
+        Some(status) => status,
+        None => return Ok(Vec::new()),
+    };
+    let mut ips: Vec<&String> = status
+        .pod_ips
+        .iter()
+        .flatten()
+        .flat_map(|p| p.ip.as_ref())
+        .collect();
+    if ips.is_empty() {
+        ips.extend(status.pod_ip.as_ref());
+    }
+    ips.into_iter()
+        .map(|ip| IpAddr::from_str(ip).with_context(|| anyhow!("parsing {:?}", ip)))
+        .collect()
+}
+
+/// Tuning for the kube-dns resolver's cache; `None` leaves hickory's default in place.
+#[derive(Debug, Clone, Default)]
+pub struct DnsCacheOptions {
+    pub cache_size: Option<usize>,
+    pub positive_min_ttl: Option<Duration>,
+    pub positive_max_ttl: Option<Duration>,
+    /// zero disables negative caching
+    pub negative_max_ttl: Option<Duration>,
+}
+
+/// A resolver for the cluster dns, which is long-lived so its cache is useful.
+pub fn kube_dns_resolver(
+    dns_servers: &[IpAddr],
+    port: u16,
+    cache: &DnsCacheOptions,
+) -> TokioAsyncResolver {
     let mut config = ResolverConfig::new();
-    for ip in ctx.dns_servers {
+    for ip in dns_servers {
         config.add_name_server(NameServerConfig {
             protocol: Protocol::Udp,
-            socket_addr: SocketAddr::new(ip, 53),
+            socket_addr: SocketAddr::new(*ip, port),
             tls_dns_name: None,
             trust_negative_responses: true,
             bind_addr: None,
         });
-        config.add_search(Name::from_str(&format!(
-            "{}.svc.{}",
-            &ctx.default_namespace, &ctx.cluster_local
-        ))?);
-        config.add_search(Name::from_str(&format!("svc.{}", &ctx.cluster_local))?);
-        config.add_search(Name::from_str(&ctx.cluster_local)?);
-    }
-    Ok(
-        hickory_resolver::TokioAsyncResolver::tokio(config, ResolverOpts::default())
-            .lookup_ip(hostname)
-            .await?
-            .into_iter()
-            .map(|v| v)
-            .collect(),
-    )
+    }
+
+    let mut opts = ResolverOpts::default();
+    if let Some(cache_size) = cache.cache_size {
+        opts.cache_size = cache_size;
+    }
+    opts.positive_min_ttl = cache.positive_min_ttl;
+    opts.positive_max_ttl = cache.positive_max_ttl;
+    opts.negative_max_ttl = cache.negative_max_ttl;
+
+    TokioAsyncResolver::tokio(config, opts)
+}
+
+/// The search list is applied here, not by the resolver, as the namespace varies by request.
+fn search_candidates(ctx: &ResolveCtx, hostname: &str) -> Vec<String> {
+    if hostname.ends_with('.') {
+        return vec![hostname.to_string()];
+    }
+    vec![
+        format!(
+            "{}.{}.svc.{}.",
+            hostname, ctx.default_namespace, ctx.cluster_local
+        ),
+        format!("{}.svc.{}.", hostname, ctx.cluster_local),
+        format!("{}.{}.", hostname, ctx.cluster_local),
+        format!("{}.", hostname),
+    ]
+}
+
+pub async fn resolve_against_kube_dns(
+    ctx: &ResolveCtx,
+    dns: &KubeDns,
+    hostname: &str,
+) -> Result<Vec<IpAddr>> {
+    if let Ok(ip) = IpAddr::from_str(hostname) {
+        return Ok(vec![ip]);
+    }
+
+    let resolver = dns.resolver();
+    for candidate in search_candidates(ctx, hostname) {
+        match resolver.lookup_ip(candidate.as_str()).await {
+            Ok(found) => return Ok(found.into_iter().collect()),
+            Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => continue,
+            Err(err) => return Err(err).with_context(|| anyhow!("looking up {:?}", candidate)),
+        }
+    }
+
+    bail!("no records for {:?}", hostname)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dashed_ips() {
+        assert_eq!(
+            "10.1.2.3".parse::<IpAddr>().unwrap(),
+            parse_dashed_ip("10-1-2-3").unwrap()
+        );
+        assert_eq!(
+            "fd00::1:2".parse::<IpAddr>().unwrap(),
+            parse_dashed_ip("fd00--1-2").unwrap()
+        );
+        assert_eq!(
+            "2001:db8:0:0:1:0:0:1".parse::<IpAddr>().unwrap(),
+            parse_dashed_ip("2001-db8-0-0-1-0-0-1").unwrap()
+        );
+        assert_eq!(
+            "::1".parse::<IpAddr>().unwrap(),
+            parse_dashed_ip("--1").unwrap()
+        );
+        assert!(parse_dashed_ip("10-1-2").is_err());
+        assert!(parse_dashed_ip("my-pod").is_err());
+        assert!(parse_dashed_ip("10-1-2-300").is_err());
+    }
+
+    fn ports(ports: &[(Option<&str>, u16)]) -> Vec<GroupPort> {
+        ports
+            .iter()
+            .map(|&(name, port)| GroupPort {
+                name: name.map(str::to_string),
+                port,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn named_ports_are_found_by_name() {
+        let group = ports(&[(Some("http"), 8080), (Some("metrics"), 9090)]);
+        let wanted = WantedPort::Named(Some("metrics".to_string()));
+        assert_eq!(Some(9090), wanted.select(&group));
+        let missing = WantedPort::Named(Some("grpc".to_string()));
+        assert_eq!(None, missing.select(&group));
+        // even with only one port, the name has to match
+        assert_eq!(None, missing.select(&ports(&[(Some("http"), 8080)])));
+    }
+
+    #[test]
+    fn unnamed_service_ports_match_unnamed_endpoint_ports() {
+        let wanted = WantedPort::Named(None);
+        assert_eq!(Some(8080), wanted.select(&ports(&[(None, 8080)])));
+        assert_eq!(None, wanted.select(&ports(&[(Some("http"), 8080)])));
+    }
+
+    #[test]
+    fn without_a_service_the_client_port_is_used() {
+        let group = ports(&[(Some("http"), 80), (Some("https"), 443)]);
+        assert_eq!(Some(443), WantedPort::Client(443).select(&group));
+        assert_eq!(None, WantedPort::Client(8443).select(&group));
+        // unless there's only one port to choose from
+        assert_eq!(
+            Some(8080),
+            WantedPort::Client(80).select(&ports(&[(None, 8080)]))
+        );
+        assert_eq!(None, WantedPort::Client(80).select(&[]));
+    }
 }
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+/// The connections currently being served, so operators can see, and end, them.
+#[derive(Clone, Default)]
+pub struct Registry {
+    next: Arc<AtomicU64>,
+    open: Arc<Mutex<BTreeMap<u64, Arc<Live>>>>,
+}
+
+/// What's known about a connection, updated as it goes.
+struct Live {
+    client: SocketAddr,
+    protocol: &'static str,
+    identity: Option<String>,
+    started: SystemTime,
+    since: Instant,
+    /// for plain http, changes with each request
+    target: Mutex<Option<(String, u16)>>,
+    up: Arc<AtomicU64>,
+    down: Arc<AtomicU64>,
+    kill: Notify,
+}
+
+/// A snapshot of a connection, for `GET /connections`.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub protocol: &'static str,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub identity: Option<String>,
+    pub started_unix_ms: u64,
+    pub duration_ms: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+/// Listed in the registry until it's dropped.
+pub struct Session {
+    id: u64,
+    registry: Registry,
+    live: Arc<Live>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.registry
+            .open
+            .lock()
+            .expect("poisoned")
+            .remove(&self.id);
+    }
+}
+
+impl Registry {
+    pub fn open(
+        &self,
+        client: SocketAddr,
+        protocol: &'static str,
+        target: Option<(String, u16)>,
+        identity: Option<String>,
+    ) -> Session {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let live = Arc::new(Live {
+            client,
+            protocol,
+            identity,
+            started: SystemTime::now(),
+            since: Instant::now(),
+            target: Mutex::new(target),
+            up: Arc::default(),
+            down: Arc::default(),
+            kill: Notify::new(),
+        });
+        self.open.lock().expect("poisoned").insert(id, live.clone());
+        Session {
+            id,
+            registry: self.clone(),
+            live,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Summary> {
+        let open = self.open.lock().expect("poisoned");
+        open.iter()
+            .map(|(id, live)| {
+                let (host, port) = match live.target.lock().expect("poisoned").clone() {
+                    Some((host, port)) => (Some(host), Some(port)),
+                    None => (None, None),
+                };
+                Summary {
+                    id: *id,
+                    client: live.client,
+                    protocol: live.protocol,
+                    host,
+                    port,
+                    identity: live.identity.clone(),
+                    started_unix_ms: live
+                        .started
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    duration_ms: live.since.elapsed().as_millis() as u64,
+                    bytes_up: live.up.load(Ordering::Relaxed),
+                    bytes_down: live.down.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    /// Ask the connection to close; false if there's no such connection.
+    pub fn terminate(&self, id: u64) -> bool {
+        match self.open.lock().expect("poisoned").get(&id) {
+            Some(live) => {
+                // stores a permit, so it isn't missed if the session isn't waiting yet
+                live.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn retarget(&self, host: &str, port: u16) {
+        *self.live.target.lock().expect("poisoned") = Some((host.to_string(), port));
+    }
+
+    /// shared with the readers, so the totals are live
+    pub fn bytes_up(&self) -> Arc<AtomicU64> {
+        self.live.up.clone()
+    }
+
+    pub fn bytes_down(&self) -> Arc<AtomicU64> {
+        self.live.down.clone()
+    }
+
+    /// Resolves once an operator has asked for the connection to be closed.
+    pub async fn terminated(&self) {
+        self.live.kill.notified().await
+    }
+}
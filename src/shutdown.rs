@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+
+/// Whether we're on our way out, and how many connections are still being served.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    draining: Arc<AtomicBool>,
+    active: Arc<AtomicUsize>,
+    finished: Arc<Notify>,
+}
+
+/// Counts as an active connection until it's dropped.
+pub struct Tracked(Shutdown);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+        self.0.finished.notify_waiters();
+    }
+}
+
+impl Shutdown {
+    pub fn track(&self) -> Tracked {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Tracked(self.clone())
+    }
+
+    /// once set, we should stop being sent new clients
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the active connections to finish, up to the deadline, or another signal.
+    pub async fn drain(&self, deadline: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        let finished = async {
+            loop {
+                // registered before checking, so a finish in between isn't missed
+                let notified = self.finished.notified();
+                let active = self.active();
+                if 0 == active {
+                    return;
+                }
+                info!("draining: {} connections remaining", active);
+                notified.await;
+            }
+        };
+        tokio::select! {
+            _ = finished => info!("drained"),
+            _ = tokio::time::sleep(deadline) => {
+                warn!("drain deadline passed, dropping {} connections", self.active())
+            }
+            signal = signalled() => {
+                warn!("received {} again, dropping {} connections", signal, self.active())
+            }
+        }
+    }
+}
+
+/// Resolves on SIGTERM, as sent by the kubelet, or SIGINT, as sent by a terminal.
+pub async fn signalled() -> &'static str {
+    let mut term = signal(SignalKind::terminate()).expect("registering for SIGTERM");
+    let mut int = signal(SignalKind::interrupt()).expect("registering for SIGINT");
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = int.recv() => "SIGINT",
+    }
+}
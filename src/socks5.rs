@@ -0,0 +1,316 @@
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::bail;
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::auth::Authenticator;
+use crate::{namespace_in_username, ConnectType, Handshake};
+
+// https://www.rfc-editor.org/rfc/rfc1928
+const VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+// https://www.rfc-editor.org/rfc/rfc1929
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCEEDED: u8 = 0x00;
+const AUTH_FAILED: u8 = 0x01;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_NOT_ALLOWED: u8 = 0x02;
+pub const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REP_HOST_UNREACHABLE: u8 = 0x04;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_TTL_EXPIRED: u8 = 0x06;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Read from the socket until `buf` has at least `want` bytes.
+async fn fill<S: AsyncRead + Unpin>(socket: &mut S, buf: &mut Vec<u8>, want: usize) -> Result<()> {
+    while buf.len() < want {
+        buf.reserve(want - buf.len());
+        let found = socket.read_buf(buf).await?;
+        if 0 == found {
+            bail!("unexpected eof reading socks5 header")
+        }
+    }
+    Ok(())
+}
+
+/// Complete the method negotiation and read the request. `buf` is what
+/// `read_initialisation` has already read, which starts with the version byte.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    buf: &mut Vec<u8>,
+    auth: &Authenticator,
+) -> Result<Handshake> {
+    // greeting: VER NMETHODS METHODS...
+    fill(socket, buf, 2).await?;
+    let greeting_len = 2 + usize::from(buf[1]);
+    fill(socket, buf, greeting_len).await?;
+    let methods = &buf[2..greeting_len];
+
+    // prefer authenticating whenever the client is willing to
+    let (start, identity, namespace) = if methods.contains(&METHOD_USERNAME_PASSWORD) {
+        socket
+            .write_all(&[VERSION, METHOD_USERNAME_PASSWORD])
+            .await?;
+
+        // VER ULEN UNAME PLEN PASSWD
+        let start = greeting_len;
+        fill(socket, buf, start + 2).await?;
+        if buf[start] != AUTH_VERSION {
+            socket.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+            bail!("invalid socks5 auth version: {:02x}", buf[start]);
+        }
+        let password_start = start + 2 + usize::from(buf[start + 1]);
+        fill(socket, buf, password_start + 1).await?;
+        let end = password_start + 1 + usize::from(buf[password_start]);
+        fill(socket, buf, end).await?;
+
+        // the username may pick the namespace; the password is the service account token
+        let namespace = namespace_in_username(&buf[start + 2..password_start]);
+        let token = String::from_utf8_lossy(&buf[password_start + 1..end]).to_string();
+        let identity = match auth.review(&token).await {
+            Ok(Some(identity)) => identity,
+            Ok(None) => {
+                socket.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+                bail!("socks5 client presented an invalid token");
+            }
+            Err(err) => {
+                socket.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+                return Err(err);
+            }
+        };
+        socket.write_all(&[AUTH_VERSION, AUTH_SUCCEEDED]).await?;
+        (end, Some(identity), namespace)
+    } else if !auth.required && methods.contains(&METHOD_NO_AUTH) {
+        socket.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+        (greeting_len, None, None)
+    } else {
+        socket.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        bail!("no acceptable socks5 methods: {:?}", methods);
+    };
+
+    // request: VER CMD RSV ATYP DST.ADDR DST.PORT
+    fill(socket, buf, start + 4).await?;
+    let header = &buf[start..start + 4];
+    if header[0] != VERSION {
+        socket.write_all(&failure(REP_GENERAL_FAILURE)).await?;
+        bail!("invalid socks5 request version: {:02x}", header[0]);
+    }
+    let command = header[1];
+    let atyp = header[3];
+
+    let addr_start = start + 4;
+    let addr_len = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            fill(socket, buf, addr_start + 1).await?;
+            1 + usize::from(buf[addr_start])
+        }
+        _ => {
+            socket
+                .write_all(&failure(REP_ADDRESS_TYPE_NOT_SUPPORTED))
+                .await?;
+            bail!("unsupported socks5 address type: {:02x}", atyp);
+        }
+    };
+    let port_start = addr_start + addr_len;
+    fill(socket, buf, port_start + 2).await?;
+
+    if command != CMD_CONNECT {
+        socket
+            .write_all(&failure(REP_COMMAND_NOT_SUPPORTED))
+            .await?;
+        bail!("unsupported socks5 command: {:02x}", command);
+    }
+
+    let port = u16::from_be_bytes(
+        buf[port_start..port_start + 2]
+            .try_into()
+            .expect("explicit slice"),
+    );
+    let addr = &buf[addr_start..port_start];
+
+    let connect = match atyp {
+        ATYP_IPV4 => {
+            let ip: [u8; 4] = addr.try_into().expect("explicit slice");
+            ConnectType::Socks5Ip {
+                addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port),
+            }
+        }
+        ATYP_IPV6 => {
+            let ip: [u8; 16] = addr.try_into().expect("explicit slice");
+            ConnectType::Socks5Ip {
+                addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port),
+            }
+        }
+        ATYP_DOMAIN => match String::from_utf8(addr[1..].to_vec()) {
+            Ok(hostname) => ConnectType::Socks5Host { hostname, port },
+            Err(err) => {
+                socket.write_all(&failure(REP_GENERAL_FAILURE)).await?;
+                return Err(err.into());
+            }
+        },
+        _ => unreachable!("address type validated above"),
+    };
+
+    Ok(Handshake {
+        connect,
+        identity,
+        namespace,
+        leftover: buf[port_start + 2..].to_vec(),
+    })
+}
+
+fn unspecified() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+}
+
+/// A reply to a request; `bound` is only meaningful for success.
+pub fn reply(code: u8, bound: SocketAddr) -> Vec<u8> {
+    let mut msg = vec![VERSION, code, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            msg.push(ATYP_IPV4);
+            msg.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            msg.push(ATYP_IPV6);
+            msg.extend_from_slice(&ip.octets());
+        }
+    }
+    msg.extend_from_slice(&bound.port().to_be_bytes());
+    msg
+}
+
+pub fn failure(code: u8) -> Vec<u8> {
+    reply(code, unspecified())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use kube::Client;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn auth() -> Authenticator {
+        let client = Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap()))
+            .expect("client");
+        Authenticator::new(client, false)
+    }
+
+    /// `request`, after a greeting offering no authentication, as if it had all been read
+    /// at once; what the handshake made of it, and everything the client was sent.
+    async fn exchange(request: &[u8]) -> (Result<Handshake>, Vec<u8>) {
+        let (mut client, mut server): (DuplexStream, DuplexStream) = tokio::io::duplex(1024);
+        let mut buf = vec![VERSION, 1, METHOD_NO_AUTH];
+        buf.extend_from_slice(request);
+        let handshake = handshake(&mut server, &mut buf, &auth()).await;
+        drop(server);
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        (handshake, sent)
+    }
+
+    /// a request has the same layout as a reply, with the command in place of the code
+    async fn round_trip(addr: SocketAddr) {
+        let mut request = reply(CMD_CONNECT, addr);
+        request.extend_from_slice(b"early");
+        let (handshake, sent) = exchange(&request).await;
+        let handshake = handshake.unwrap();
+        assert_eq!(vec![VERSION, METHOD_NO_AUTH], sent);
+        assert!(
+            matches!(handshake.connect, ConnectType::Socks5Ip { addr: found } if found == addr)
+        );
+        assert_eq!(b"early", handshake.leftover.as_slice());
+        assert!(handshake.identity.is_none());
+    }
+
+    #[tokio::test]
+    async fn ipv4_requests_round_trip() {
+        round_trip("10.1.2.3:8080".parse().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn ipv6_requests_round_trip() {
+        round_trip("[fd00::1:2]:443".parse().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn domain_requests() {
+        let mut request = vec![VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&80u16.to_be_bytes());
+        request.extend_from_slice(b"GET");
+        let (handshake, _) = exchange(&request).await;
+        let handshake = handshake.unwrap();
+        assert!(matches!(
+            handshake.connect,
+            ConnectType::Socks5Host { ref hostname, port: 80 } if hostname == "example.com"
+        ));
+        assert_eq!(b"GET", handshake.leftover.as_slice());
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_answered() {
+        let mut failed = vec![VERSION, METHOD_NO_AUTH];
+        failed.extend(failure(REP_GENERAL_FAILURE));
+
+        let (handshake, sent) =
+            exchange(&[0x04, CMD_CONNECT, 0, ATYP_IPV4, 1, 2, 3, 4, 0, 80]).await;
+        assert!(handshake.is_err());
+        assert_eq!(failed, sent);
+
+        let (handshake, sent) =
+            exchange(&[VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 2, 0xff, 0xfe, 0, 80]).await;
+        assert!(handshake.is_err());
+        assert_eq!(failed, sent);
+
+        let (handshake, sent) = exchange(&[VERSION, 0x02, 0, ATYP_IPV4, 1, 2, 3, 4, 0, 80]).await;
+        assert!(handshake.is_err());
+        assert_eq!(&failure(REP_COMMAND_NOT_SUPPORTED)[..], &sent[2..]);
+    }
+
+    #[test]
+    fn replies_carry_the_bound_address() {
+        assert_eq!(
+            vec![
+                VERSION,
+                REP_SUCCEEDED,
+                0,
+                ATYP_IPV4,
+                10,
+                0,
+                0,
+                1,
+                0x1f,
+                0x90
+            ],
+            reply(REP_SUCCEEDED, "10.0.0.1:8080".parse().unwrap())
+        );
+        let v6 = reply(REP_SUCCEEDED, "[::1]:1".parse().unwrap());
+        assert_eq!(&[VERSION, REP_SUCCEEDED, 0, ATYP_IPV6][..], &v6[..4]);
+        assert_eq!(4 + 16 + 2, v6.len());
+        assert_eq!(
+            vec![VERSION, REP_NOT_ALLOWED, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0],
+            failure(REP_NOT_ALLOWED)
+        );
+    }
+}
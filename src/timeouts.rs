@@ -0,0 +1,174 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::metrics;
+
+/// Which limit ran out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stage {
+    Handshake,
+    Resolve,
+    Connect,
+    Idle,
+    Lifetime,
+}
+
+impl Stage {
+    /// for logs and labelling metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Handshake => "handshake",
+            Stage::Resolve => "resolve",
+            Stage::Connect => "connect",
+            Stage::Idle => "idle",
+            Stage::Lifetime => "lifetime",
+        }
+    }
+}
+
+/// The error for a limit running out, which can be picked out of an `anyhow::Error` chain.
+#[derive(Debug)]
+pub struct Expired {
+    pub stage: Stage,
+    pub after: Duration,
+}
+
+impl fmt::Display for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timeout after {:?}", self.stage.name(), self.after)
+    }
+}
+
+impl std::error::Error for Expired {}
+
+impl Expired {
+    fn new(stage: Stage, after: Duration) -> Expired {
+        metrics::observe_timeout(stage.name());
+        Expired { stage, after }
+    }
+
+    /// Whether the error is, or was caused by, a limit.
+    pub fn is_in(err: &anyhow::Error) -> bool {
+        err.chain().any(|cause| cause.is::<Expired>())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Timeouts {
+    pub handshake: Duration,
+    pub resolve: Duration,
+    pub connect: Duration,
+    /// nothing read in either direction for this long
+    pub idle: Option<Duration>,
+    pub lifetime: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Fail with `Expired` if the future takes longer than the stage allows.
+    pub async fn limit<T>(&self, stage: Stage, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let after = match stage {
+            Stage::Handshake => self.handshake,
+            Stage::Resolve => self.resolve,
+            Stage::Connect => self.connect,
+            Stage::Idle | Stage::Lifetime => unreachable!("tunnels use `expire`"),
+        };
+        match tokio::time::timeout(after, fut).await {
+            Ok(result) => result,
+            Err(_) => Err(Expired::new(stage, after).into()),
+        }
+    }
+
+    /// Runs until the tunnel has been idle, or open, for too long; forever if neither is set.
+    pub async fn expire(&self, activity: &Activity) -> Expired {
+        let lifetime = async {
+            match self.lifetime {
+                Some(after) => {
+                    tokio::time::sleep(after).await;
+                    Expired::new(Stage::Lifetime, after)
+                }
+                None => futures::future::pending().await,
+            }
+        };
+        let idle = async {
+            let after = match self.idle {
+                Some(after) => after,
+                None => futures::future::pending().await,
+            };
+            loop {
+                let quiet = activity.quiet_for();
+                if quiet >= after {
+                    return Expired::new(Stage::Idle, after);
+                }
+                tokio::time::sleep(after - quiet).await;
+            }
+        };
+        tokio::select! {
+            expired = lifetime => expired,
+            expired = idle => expired,
+        }
+    }
+}
+
+/// When anything was last read through a tunnel, shared by both directions.
+#[derive(Clone)]
+pub struct Activity {
+    start: Instant,
+    /// millis since `start`
+    last: Arc<AtomicU64>,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            last: Arc::default(),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    fn quiet_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    pub fn watch<R>(&self, inner: R) -> Watched<R> {
+        Watched {
+            inner,
+            activity: self.clone(),
+        }
+    }
+}
+
+/// Records activity whenever anything is read through it.
+pub struct Watched<R> {
+    inner: R,
+    activity: Activity,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Watched<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() != before {
+            self.activity.touch();
+        }
+        result
+    }
+}
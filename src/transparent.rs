@@ -0,0 +1,104 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::TransparentMode;
+
+/// Listen for connections redirected by the firewall, e.g. for `REDIRECT`, skipping our own:
+/// `iptables -t nat -A OUTPUT -p tcp -d 10.0.0.0/8 -m owner ! --uid-owner begonia
+/// -j REDIRECT --to-ports 3440`
+pub fn bind(addr: SocketAddr, mode: TransparentMode) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if mode == TransparentMode::Tproxy {
+        // lets us accept connections for addresses which aren't ours; needs CAP_NET_ADMIN
+        socket
+            .set_ip_transparent(true)
+            .with_context(|| anyhow!("enabling IP_TRANSPARENT for tproxy"))?;
+    }
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| anyhow!("binding to {:?}", addr))?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Accept from the listener, if there is one; otherwise, never.
+pub async fn accept(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Finds where redirected connections were going, and refuses to send them back to us.
+#[derive(Clone)]
+pub struct Interceptor {
+    mode: TransparentMode,
+    /// every address we accept connections on
+    listening: Arc<Vec<SocketAddr>>,
+    /// the addresses on our interfaces, which unspecified listen addresses cover
+    own: Arc<Vec<IpAddr>>,
+}
+
+impl Interceptor {
+    pub fn new(mode: TransparentMode, listening: Vec<SocketAddr>, own: Vec<IpAddr>) -> Interceptor {
+        Interceptor {
+            mode,
+            listening: Arc::new(listening),
+            own: Arc::new(own.into_iter().map(|ip| ip.to_canonical()).collect()),
+        }
+    }
+
+    /// Where the client was trying to go before we intercepted it.
+    pub fn original_destination(&self, socket: &TcpStream) -> Result<SocketAddr> {
+        let local = socket.local_addr()?;
+        let original = if self.mode == TransparentMode::Tproxy {
+            // the connection was handed to us as-is, addressed to the real destination
+            local
+        } else {
+            let socket = SockRef::from(socket);
+            let original = if local.ip().to_canonical().is_ipv4() {
+                socket.original_dst()
+            } else {
+                socket.original_dst_ipv6()
+            }
+            .with_context(|| anyhow!("no original destination; was the connection redirected?"))?;
+            let original = original.as_socket().ok_or_else(|| {
+                anyhow!("original destination isn't an ip address: {:?}", original)
+            })?;
+            if original == local {
+                bail!(
+                    "connection was redirected to where it was already going, {}",
+                    local
+                );
+            }
+            original
+        };
+        // ipv4 through a dual-stack listener arrives mapped, which cidr rules wouldn't match
+        let original = SocketAddr::new(original.ip().to_canonical(), original.port());
+        // dialling ourselves would be redirected back here, over and over
+        if self.is_us(original) {
+            bail!("refusing to connect to ourselves, at {}", original);
+        }
+        Ok(original)
+    }
+
+    fn is_us(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip();
+        self.listening.iter().any(|listening| {
+            listening.port() == addr.port()
+                && (listening.ip().to_canonical() == ip
+                    || (listening.ip().is_unspecified()
+                        && (ip.is_loopback() || ip.is_unspecified() || self.own.contains(&ip))))
+        })
+    }
+}